@@ -0,0 +1,95 @@
+use glam::*;
+
+use crate::rasterizer::{BlendMode, CompareFunc};
+
+const END_OF_LIST: u32 = u32::MAX;
+
+struct Fragment {
+    depth: f32,
+    color: Vec4,
+    blend_mode: BlendMode,
+    color_mask: BVec4A,
+    next: u32,
+}
+
+// Per-sample linked lists of transparent fragments, laid out like a GPU
+// A-buffer: `heads` holds the last fragment pushed for every sample and each
+// fragment points to the one pushed before it
+pub struct ABuffer {
+    heads: Vec<u32>,
+    fragments: Vec<Fragment>,
+}
+
+impl ABuffer {
+    pub fn new(sample_count: usize) -> Self {
+        Self {
+            heads: vec![END_OF_LIST; sample_count],
+            fragments: vec![],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.heads.fill(END_OF_LIST);
+        self.fragments.clear();
+    }
+
+    pub fn push(
+        &mut self,
+        sample: usize,
+        depth: f32,
+        color: Vec4,
+        blend_mode: BlendMode,
+        color_mask: BVec4A,
+    ) {
+        self.fragments.push(Fragment {
+            depth,
+            color,
+            blend_mode,
+            color_mask,
+            next: self.heads[sample],
+        });
+        self.heads[sample] = (self.fragments.len() - 1) as u32;
+    }
+
+    // Blends the fragments of every sample onto `samples` from back to front,
+    // the lists are empty afterwards. Fragments are only depth tested when
+    // pushed, so the ones an opaque draw covered later are dropped here
+    // against the final `depth` of the sample, which only opaque draws write.
+    pub fn composite(
+        &mut self,
+        samples: &mut [Vec4],
+        depth: &[f32],
+        depth_func: CompareFunc,
+        reversed_z: bool,
+    ) {
+        let mut list: Vec<&Fragment> = vec![];
+
+        for (sample, &head) in self.heads.iter().enumerate() {
+            let mut next = head;
+            while next != END_OF_LIST {
+                let fragment = &self.fragments[next as usize];
+                if depth_func.test(fragment.depth, depth[sample]) {
+                    list.push(fragment);
+                }
+                next = fragment.next;
+            }
+            if list.is_empty() {
+                continue;
+            }
+
+            // larger depth is farther away, unless the depth is reversed
+            if reversed_z {
+                list.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+            } else {
+                list.sort_by(|a, b| b.depth.total_cmp(&a.depth));
+            }
+            for fragment in list.drain(..) {
+                let dst = samples[sample];
+                let blended = fragment.blend_mode.blend(fragment.color, dst);
+                samples[sample] = Vec4::select(fragment.color_mask, blended, dst);
+            }
+        }
+
+        self.clear();
+    }
+}
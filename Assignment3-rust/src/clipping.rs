@@ -0,0 +1,128 @@
+use glam::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipMode {
+    // only the near and far planes, x and y are left to the screen bounds
+    NearFar,
+    // all six frustum planes
+    Frustum,
+}
+
+// A vertex before the perspective divide, with everything that gets
+// interpolated across the triangle. Attributes are linear in clip space so
+// new vertices on a clip plane are a plain lerp of the endpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct ClipVertex {
+    pub position: Vec4,
+    pub view_pos: Vec3,
+    pub color: Vec4,
+    pub tex_coord: Vec2,
+    pub normal: Vec3,
+    pub tangent: Vec4,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            position: self.position.lerp(other.position, t),
+            view_pos: self.view_pos.lerp(other.view_pos, t),
+            color: self.color.lerp(other.color, t),
+            tex_coord: self.tex_coord.lerp(other.tex_coord, t),
+            normal: self.normal.lerp(other.normal, t),
+            tangent: self.tangent.lerp(other.tangent, t),
+        }
+    }
+}
+
+// Signed distances to the planes, inside is >= 0
+const NEAR: fn(Vec4) -> f32 = |p| p.w + p.z;
+const FAR: fn(Vec4) -> f32 = |p| p.w - p.z;
+const LEFT: fn(Vec4) -> f32 = |p| p.w + p.x;
+const RIGHT: fn(Vec4) -> f32 = |p| p.w - p.x;
+const BOTTOM: fn(Vec4) -> f32 = |p| p.w + p.y;
+const TOP: fn(Vec4) -> f32 = |p| p.w - p.y;
+// reversed-Z projections put the near plane at z = w and the far one at z = 0
+const REVERSED_NEAR: fn(Vec4) -> f32 = |p| p.w - p.z;
+const REVERSED_FAR: fn(Vec4) -> f32 = |p| p.z;
+
+impl ClipMode {
+    fn planes(&self, reversed_z: bool) -> &'static [fn(Vec4) -> f32] {
+        match (self, reversed_z) {
+            (ClipMode::NearFar, false) => &[NEAR, FAR],
+            (ClipMode::NearFar, true) => &[REVERSED_NEAR, REVERSED_FAR],
+            (ClipMode::Frustum, false) => &[NEAR, FAR, LEFT, RIGHT, BOTTOM, TOP],
+            (ClipMode::Frustum, true) => &[REVERSED_NEAR, REVERSED_FAR, LEFT, RIGHT, BOTTOM, TOP],
+        }
+    }
+}
+
+// Sutherland-Hodgman against each plane in turn. The result is a convex
+// polygon in the winding of the input, empty when fully outside.
+pub fn clip_polygon(vertices: &[ClipVertex], mode: ClipMode, reversed_z: bool) -> Vec<ClipVertex> {
+    let mut polygon = vertices.to_vec();
+
+    for plane in mode.planes(reversed_z) {
+        if polygon.is_empty() {
+            break;
+        }
+
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (k, current) in polygon.iter().enumerate() {
+            let next = &polygon[(k + 1) % polygon.len()];
+            let (d0, d1) = (plane(current.position), plane(next.position));
+
+            if d0 >= 0. {
+                clipped.push(*current);
+            }
+            if (d0 >= 0.) != (d1 >= 0.) {
+                clipped.push(current.lerp(next, d0 / (d0 - d1)));
+            }
+        }
+        polygon = clipped;
+    }
+
+    polygon
+}
+
+// Fan triangulation of a clipped (convex) polygon
+pub fn clip_triangle(
+    vertices: &[ClipVertex; 3],
+    mode: ClipMode,
+    reversed_z: bool,
+) -> Vec<[ClipVertex; 3]> {
+    // fast path, nothing to do for triangles inside every plane
+    if mode
+        .planes(reversed_z)
+        .iter()
+        .all(|plane| vertices.iter().all(|v| plane(v.position) >= 0.))
+    {
+        return vec![*vertices];
+    }
+
+    let polygon = clip_polygon(vertices, mode, reversed_z);
+    (1..polygon.len().saturating_sub(1))
+        .map(|k| [polygon[0], polygon[k], polygon[k + 1]])
+        .collect()
+}
+
+// Parametric clipping of a line segment, None when fully outside
+pub fn clip_line(a: Vec4, b: Vec4, mode: ClipMode, reversed_z: bool) -> Option<(Vec4, Vec4)> {
+    let (mut t0, mut t1) = (0f32, 1f32);
+
+    for plane in mode.planes(reversed_z) {
+        let (d0, d1) = (plane(a), plane(b));
+        if d0 < 0. && d1 < 0. {
+            return None;
+        }
+        if d0 < 0. {
+            t0 = t0.max(d0 / (d0 - d1));
+        } else if d1 < 0. {
+            t1 = t1.min(d0 / (d0 - d1));
+        }
+    }
+
+    if t0 > t1 {
+        return None;
+    }
+    Some((a.lerp(b, t0), a.lerp(b, t1)))
+}
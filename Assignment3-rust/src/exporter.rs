@@ -0,0 +1,197 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    mesh::{Material, Mesh},
+    texture::Texture,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlyEncoding {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+fn to_io_error(err: image::ImageError) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+// Textures only live in memory after loading, write them next to the MTL
+fn save_material_texture(
+    dir: &Path,
+    stem: &str,
+    index: usize,
+    kind: &str,
+    texture: &Option<Texture>,
+) -> std::io::Result<Option<String>> {
+    match texture {
+        Some(texture) => {
+            let file_name = format!("{stem}_{index}_{kind}.png");
+            texture
+                .save(dir.join(&file_name).to_str().unwrap())
+                .map_err(to_io_error)?;
+            Ok(Some(file_name))
+        }
+        None => Ok(None),
+    }
+}
+
+fn write_mtl(path: &Path, materials: &[Material]) -> std::io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let mut out = BufWriter::new(File::create(path)?);
+
+    for (index, material) in materials.iter().enumerate() {
+        let c = material.base_color;
+        // inverse of the roughness mapping used when loading MTL files
+        let ns = 2. / (material.roughness * material.roughness).max(1e-4) - 2.;
+
+        writeln!(out, "newmtl {}", material_name(material, index))?;
+        writeln!(out, "Kd {} {} {}", c.x, c.y, c.z)?;
+        writeln!(out, "Ke {} {} {}", material.emissive.x, material.emissive.y, material.emissive.z)?;
+        writeln!(out, "Ns {}", ns)?;
+        writeln!(out, "d {}", c.w)?;
+        // PBR extension to MTL, ignored by readers that don't know it
+        writeln!(out, "Pm {}", material.metallic)?;
+        writeln!(out, "Pr {}", material.roughness)?;
+        writeln!(out, "illum 2")?;
+
+        let maps = [
+            ("map_Kd", "base_color", &material.base_color_texture),
+            ("map_Bump", "normal", &material.normal_texture),
+            ("map_Ke", "emissive", &material.emissive_texture),
+            // not part of MTL either, the glTF packing with the roughness
+            // in green and the metallic in blue
+            ("map_Pmr", "metallic_roughness", &material.metallic_roughness_texture),
+            ("map_ao", "occlusion", &material.occlusion_texture),
+        ];
+        for (keyword, kind, texture) in maps {
+            if let Some(file_name) = save_material_texture(dir, &stem, index, kind, texture)? {
+                writeln!(out, "{keyword} {file_name}")?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    out.flush()
+}
+
+fn material_name(material: &Material, index: usize) -> String {
+    if material.name.is_empty() {
+        format!("material_{index}")
+    } else {
+        material.name.replace(char::is_whitespace, "_")
+    }
+}
+
+// Writes the meshes as OBJ objects, keeping the original polygons rather
+// than the triangulation. Materials go to a sibling .mtl file.
+pub fn save_obj(path: &str, meshes: &[Mesh], materials: &[Material]) -> std::io::Result<()> {
+    let path = Path::new(path);
+    let mut out = BufWriter::new(File::create(path)?);
+
+    if !materials.is_empty() {
+        let mtl_path = path.with_extension("mtl");
+        write_mtl(&mtl_path, materials)?;
+        writeln!(out, "mtllib {}", mtl_path.file_name().unwrap().to_string_lossy())?;
+    }
+
+    // OBJ indices are 1-based and global across objects
+    let mut offset = 1;
+
+    for mesh in meshes {
+        writeln!(out, "o {}", mesh.name.replace(char::is_whitespace, "_"))?;
+
+        for p in &mesh.positions {
+            writeln!(out, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        for t in &mesh.tex_coords {
+            writeln!(out, "vt {} {}", t.x, t.y)?;
+        }
+        for n in &mesh.normals {
+            writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+
+        if let Some(material) = mesh.material.and_then(|it| materials.get(it).map(|m| (it, m))) {
+            writeln!(out, "usemtl {}", material_name(material.1, material.0))?;
+        }
+
+        for polygon in &mesh.polygons {
+            write!(out, "f")?;
+            for &i in polygon {
+                let i = i as usize + offset;
+                write!(out, " {i}/{i}/{i}")?;
+            }
+            writeln!(out)?;
+        }
+
+        offset += mesh.positions.len();
+    }
+
+    out.flush()
+}
+
+pub fn save_ply(path: &str, mesh: &Mesh, encoding: PlyEncoding) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    let format = match encoding {
+        PlyEncoding::Ascii => "ascii",
+        PlyEncoding::BinaryLittleEndian => "binary_little_endian",
+    };
+
+    writeln!(out, "ply")?;
+    writeln!(out, "format {format} 1.0")?;
+    writeln!(out, "comment {}", mesh.name)?;
+    writeln!(out, "element vertex {}", mesh.positions.len())?;
+    for property in ["x", "y", "z", "nx", "ny", "nz", "s", "t"] {
+        writeln!(out, "property float {property}")?;
+    }
+    // most readers expect uchar counts, faces with more vertices need more
+    let wide_counts = mesh.polygons.iter().any(|it| it.len() > u8::MAX as usize);
+    let count_type = if wide_counts { "uint" } else { "uchar" };
+    writeln!(out, "element face {}", mesh.polygons.len())?;
+    writeln!(out, "property list {count_type} int vertex_indices")?;
+    writeln!(out, "end_header")?;
+
+    for (i, p) in mesh.positions.iter().enumerate() {
+        let n = mesh.normals[i];
+        let t = mesh.tex_coords[i];
+        let vertex = [p.x, p.y, p.z, n.x, n.y, n.z, t.x, t.y];
+
+        match encoding {
+            PlyEncoding::Ascii => {
+                let line: Vec<String> = vertex.iter().map(|it| it.to_string()).collect();
+                writeln!(out, "{}", line.join(" "))?;
+            }
+            PlyEncoding::BinaryLittleEndian => {
+                for it in vertex {
+                    out.write_all(&it.to_le_bytes())?;
+                }
+            }
+        }
+    }
+
+    for polygon in &mesh.polygons {
+        match encoding {
+            PlyEncoding::Ascii => {
+                let line: Vec<String> = polygon.iter().map(|it| it.to_string()).collect();
+                writeln!(out, "{} {}", polygon.len(), line.join(" "))?;
+            }
+            PlyEncoding::BinaryLittleEndian => {
+                if wide_counts {
+                    out.write_all(&(polygon.len() as u32).to_le_bytes())?;
+                } else {
+                    out.write_all(&[polygon.len() as u8])?;
+                }
+                for &it in polygon {
+                    out.write_all(&(it as i32).to_le_bytes())?;
+                }
+            }
+        }
+    }
+
+    out.flush()
+}
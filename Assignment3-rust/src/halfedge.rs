@@ -0,0 +1,612 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    f32::consts::PI,
+};
+
+use glam::*;
+
+use crate::mesh::Mesh;
+
+#[derive(Clone, Copy)]
+pub struct HalfEdge {
+    // vertex the half-edge starts from
+    pub origin: u32,
+    pub next: u32,
+    // None on boundaries and non-manifold edges
+    pub twin: Option<u32>,
+    pub face: u32,
+    // texture coordinate of the face corner at `origin`, kept per corner so
+    // uv seams don't split the surface
+    pub tex_coord: Vec2,
+}
+
+// Triangle half-edge mesh. Vertices are welded by position, so the surface is
+// connected across the uv and normal seams the loaders split vertices at.
+// Half-edges of face `f` are stored at `3f`, `3f + 1` and `3f + 2`.
+#[derive(Clone)]
+pub struct HalfEdgeMesh {
+    pub name: String,
+    pub positions: Vec<Vec3>,
+    pub half_edges: Vec<HalfEdge>,
+    pub material: Option<usize>,
+}
+
+impl HalfEdgeMesh {
+    pub fn from_mesh(mesh: &Mesh) -> Self {
+        let mut welded: HashMap<[u32; 3], u32> = HashMap::new();
+        let mut positions = vec![];
+
+        let remap: Vec<u32> = mesh
+            .positions
+            .iter()
+            .map(|p| {
+                *welded
+                    .entry(p.to_array().map(f32::to_bits))
+                    .or_insert_with(|| {
+                        positions.push(*p);
+                        positions.len() as u32 - 1
+                    })
+            })
+            .collect();
+
+        let faces: Vec<([u32; 3], [Vec2; 3])> = mesh
+            .indices
+            .iter()
+            .map(|tri| {
+                let tri = tri.to_array();
+                (
+                    tri.map(|i| remap[i as usize]),
+                    tri.map(|i| mesh.tex_coords[i as usize]),
+                )
+            })
+            .collect();
+
+        let mut result = Self::from_faces(positions, &faces);
+        result.name = mesh.name.clone();
+        result.material = mesh.material;
+        result
+    }
+
+    fn from_faces(positions: Vec<Vec3>, faces: &[([u32; 3], [Vec2; 3])]) -> Self {
+        let mut half_edges = Vec::with_capacity(faces.len() * 3);
+
+        for (f, (tri, tex_coords)) in faces.iter().enumerate() {
+            for k in 0..3 {
+                half_edges.push(HalfEdge {
+                    origin: tri[k],
+                    next: (3 * f + (k + 1) % 3) as u32,
+                    twin: None,
+                    face: f as u32,
+                    tex_coord: tex_coords[k],
+                });
+            }
+        }
+
+        // directed edges seen more than once are non-manifold, leave them
+        // without twins so they behave like boundaries
+        let mut directed: HashMap<(u32, u32), Option<u32>> = HashMap::new();
+        for h in 0..half_edges.len() {
+            let key = (
+                half_edges[h].origin,
+                half_edges[half_edges[h].next as usize].origin,
+            );
+            directed
+                .entry(key)
+                .and_modify(|it| *it = None)
+                .or_insert(Some(h as u32));
+        }
+
+        for h in 0..half_edges.len() {
+            let (a, b) = (
+                half_edges[h].origin,
+                half_edges[half_edges[h].next as usize].origin,
+            );
+            if let (Some(Some(_)), Some(Some(twin))) =
+                (directed.get(&(a, b)), directed.get(&(b, a)))
+            {
+                half_edges[h].twin = Some(*twin);
+            }
+        }
+
+        Self {
+            name: String::new(),
+            positions,
+            half_edges,
+            material: None,
+        }
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.half_edges.len() / 3
+    }
+
+    #[inline]
+    pub fn destination(&self, h: u32) -> u32 {
+        self.half_edges[self.half_edges[h as usize].next as usize].origin
+    }
+
+    // The vertex across the face from half-edge `h`
+    #[inline]
+    pub fn opposite(&self, h: u32) -> u32 {
+        let next = self.half_edges[h as usize].next;
+        self.destination(next)
+    }
+
+    fn face(&self, f: usize) -> [u32; 3] {
+        [0, 1, 2].map(|k| self.half_edges[3 * f + k].origin)
+    }
+
+    fn faces(&self) -> Vec<([u32; 3], [Vec2; 3])> {
+        (0..self.face_count())
+            .map(|f| {
+                (
+                    self.face(f),
+                    [0, 1, 2].map(|k| self.half_edges[3 * f + k].tex_coord),
+                )
+            })
+            .collect()
+    }
+
+    // (neighbours, boundary neighbours) of every vertex
+    fn adjacency(&self) -> (Vec<Vec<u32>>, Vec<Vec<u32>>) {
+        let mut neighbours = vec![vec![]; self.positions.len()];
+        let mut boundary = vec![vec![]; self.positions.len()];
+
+        for (h, half_edge) in self.half_edges.iter().enumerate() {
+            let (a, b) = (half_edge.origin, self.destination(h as u32));
+            match half_edge.twin {
+                // interior edges are visited from both sides
+                Some(_) => neighbours[a as usize].push(b),
+                None => {
+                    neighbours[a as usize].push(b);
+                    neighbours[b as usize].push(a);
+                    boundary[a as usize].push(b);
+                    boundary[b as usize].push(a);
+                }
+            }
+        }
+
+        for it in neighbours.iter_mut() {
+            it.sort_unstable();
+            it.dedup();
+        }
+
+        (neighbours, boundary)
+    }
+
+    // One level of Loop subdivision, boundaries use the cubic B-spline rules.
+    // Texture coordinates are interpolated linearly within each face.
+    pub fn subdivide_loop(&self) -> Self {
+        let (neighbours, boundary) = self.adjacency();
+
+        let mut positions: Vec<Vec3> = self
+            .positions
+            .iter()
+            .enumerate()
+            .map(|(v, &p)| {
+                if boundary[v].len() == 2 {
+                    let (a, b) = (boundary[v][0] as usize, boundary[v][1] as usize);
+                    0.75 * p + 0.125 * (self.positions[a] + self.positions[b])
+                } else if !boundary[v].is_empty() || neighbours[v].is_empty() {
+                    // corners and non-manifold vertices stay where they are
+                    p
+                } else {
+                    let n = neighbours[v].len() as f32;
+                    let t = 0.375 + 0.25 * (2. * PI / n).cos();
+                    let beta = (0.625 - t * t) / n;
+                    let sum: Vec3 = neighbours[v]
+                        .iter()
+                        .map(|&it| self.positions[it as usize])
+                        .sum();
+                    (1. - n * beta) * p + beta * sum
+                }
+            })
+            .collect();
+
+        // one new vertex per edge, shared by the half-edge and its twin
+        let mut edge_vertex = vec![u32::MAX; self.half_edges.len()];
+        for h in 0..self.half_edges.len() as u32 {
+            if edge_vertex[h as usize] != u32::MAX {
+                continue;
+            }
+
+            let a = self.positions[self.half_edges[h as usize].origin as usize];
+            let b = self.positions[self.destination(h) as usize];
+
+            let p = match self.half_edges[h as usize].twin {
+                Some(twin) => {
+                    let c = self.positions[self.opposite(h) as usize];
+                    let d = self.positions[self.opposite(twin) as usize];
+                    0.375 * (a + b) + 0.125 * (c + d)
+                }
+                None => 0.5 * (a + b),
+            };
+
+            let index = positions.len() as u32;
+            positions.push(p);
+            edge_vertex[h as usize] = index;
+            if let Some(twin) = self.half_edges[h as usize].twin {
+                edge_vertex[twin as usize] = index;
+            }
+        }
+
+        let mut faces = Vec::with_capacity(self.face_count() * 4);
+        for f in 0..self.face_count() {
+            let [a, b, c] = self.face(f);
+            let [ab, bc, ca] = [0, 1, 2].map(|k| edge_vertex[3 * f + k]);
+            let [ta, tb, tc] = [0, 1, 2].map(|k| self.half_edges[3 * f + k].tex_coord);
+            let (tab, tbc, tca) = ((ta + tb) * 0.5, (tb + tc) * 0.5, (tc + ta) * 0.5);
+
+            faces.push(([a, ab, ca], [ta, tab, tca]));
+            faces.push(([ab, b, bc], [tab, tb, tbc]));
+            faces.push(([ca, bc, c], [tca, tbc, tc]));
+            faces.push(([ab, bc, ca], [tab, tbc, tca]));
+        }
+
+        let mut result = Self::from_faces(positions, &faces);
+        result.name = self.name.clone();
+        result.material = self.material;
+        result
+    }
+
+    // Garland-Heckbert quadric error metric simplification, collapses edges
+    // until at most `target_faces` triangles are left
+    pub fn simplify(&self, target_faces: usize) -> Self {
+        let (_, mut boundary) = self.adjacency();
+
+        let mut positions = self.positions.clone();
+        let mut faces = self.faces();
+        let mut face_alive = vec![true; faces.len()];
+        let mut alive_count = faces.len();
+
+        let mut vertex_faces: Vec<Vec<u32>> = vec![vec![]; positions.len()];
+        let mut quadrics = vec![Quadric::default(); positions.len()];
+
+        for (f, (tri, _)) in faces.iter().enumerate() {
+            let [a, b, c] = tri.map(|i| positions[i as usize]);
+            let n = (b - a).cross(c - a).normalize_or_zero();
+            let q = Quadric::from_plane(n, -n.dot(a));
+            for &v in tri {
+                vertex_faces[v as usize].push(f as u32);
+                quadrics[v as usize] += q;
+            }
+        }
+
+        // boundaries get a heavily weighted plane perpendicular to the face
+        // so the outline of open meshes is preserved
+        for h in 0..self.half_edges.len() as u32 {
+            if self.half_edges[h as usize].twin.is_some() {
+                continue;
+            }
+            let (a, b) = (self.half_edges[h as usize].origin, self.destination(h));
+            let (pa, pb) = (positions[a as usize], positions[b as usize]);
+            let face_normal = {
+                let [x, y, z] = self
+                    .face(self.half_edges[h as usize].face as usize)
+                    .map(|i| positions[i as usize]);
+                (y - x).cross(z - x)
+            };
+            let n = (pb - pa).cross(face_normal).normalize_or_zero();
+            let q = Quadric::from_plane(n, -n.dot(pa)) * 1000.;
+            quadrics[a as usize] += q;
+            quadrics[b as usize] += q;
+        }
+
+        let mut versions = vec![0u32; positions.len()];
+        let mut heap = BinaryHeap::new();
+
+        let push_edge = |heap: &mut BinaryHeap<_>,
+                         positions: &[Vec3],
+                         quadrics: &[Quadric],
+                         versions: &[u32],
+                         a: u32,
+                         b: u32| {
+            let q = quadrics[a as usize] + quadrics[b as usize];
+            let (pa, pb) = (positions[a as usize], positions[b as usize]);
+            let target = q.optimal_point().unwrap_or_else(|| {
+                [pa, pb, (pa + pb) * 0.5]
+                    .into_iter()
+                    .min_by(|x, y| q.error(*x).total_cmp(&q.error(*y)))
+                    .unwrap()
+            });
+            heap.push(Reverse(Collapse {
+                cost: q.error(target),
+                a,
+                b,
+                target,
+                versions: (versions[a as usize], versions[b as usize]),
+            }));
+        };
+
+        for h in 0..self.half_edges.len() as u32 {
+            let (a, b) = (self.half_edges[h as usize].origin, self.destination(h));
+            if self.half_edges[h as usize]
+                .twin
+                .is_none_or(|twin| h < twin)
+            {
+                push_edge(&mut heap, &positions, &quadrics, &versions, a, b);
+            }
+        }
+
+        let neighbours_of = |faces: &[([u32; 3], [Vec2; 3])], vertex_faces: &[Vec<u32>], v: u32| {
+            let mut result: Vec<u32> = vertex_faces[v as usize]
+                .iter()
+                .flat_map(|&f| faces[f as usize].0)
+                .filter(|&it| it != v)
+                .collect();
+            result.sort_unstable();
+            result.dedup();
+            result
+        };
+
+        while alive_count > target_faces {
+            let Some(Reverse(collapse)) = heap.pop() else {
+                break;
+            };
+            let (a, b) = (collapse.a, collapse.b);
+            if collapse.versions != (versions[a as usize], versions[b as usize]) {
+                continue;
+            }
+
+            // never collapse two boundary vertices across the interior
+            let boundary_a = !boundary[a as usize].is_empty();
+            let boundary_b = !boundary[b as usize].is_empty();
+            if boundary_a && boundary_b && !boundary[a as usize].contains(&b) {
+                continue;
+            }
+
+            // link condition, the edge must not pinch the surface
+            let shared_faces = vertex_faces[a as usize]
+                .iter()
+                .filter(|&&f| faces[f as usize].0.contains(&b))
+                .count();
+            let na = neighbours_of(&faces, &vertex_faces, a);
+            let nb = neighbours_of(&faces, &vertex_faces, b);
+            let shared_neighbours = na.iter().filter(|it| nb.contains(it)).count();
+            if shared_neighbours != shared_faces {
+                continue;
+            }
+
+            // reject collapses that fold a surviving face over
+            let flips = [a, b].iter().any(|&v| {
+                vertex_faces[v as usize].iter().any(|&f| {
+                    let tri = faces[f as usize].0;
+                    if tri.contains(&a) && tri.contains(&b) {
+                        return false;
+                    }
+                    let before = tri.map(|i| positions[i as usize]);
+                    let after = tri.map(|i| {
+                        if i == v {
+                            collapse.target
+                        } else {
+                            positions[i as usize]
+                        }
+                    });
+                    let n0 = (before[1] - before[0]).cross(before[2] - before[0]);
+                    let n1 = (after[1] - after[0]).cross(after[2] - after[0]);
+                    n0.dot(n1) <= 0.
+                })
+            });
+            if flips {
+                continue;
+            }
+
+            positions[a as usize] = collapse.target;
+            quadrics[a as usize] = quadrics[a as usize] + quadrics[b as usize];
+
+            for f in std::mem::take(&mut vertex_faces[b as usize]) {
+                let tri = &mut faces[f as usize].0;
+                if tri.contains(&a) {
+                    face_alive[f as usize] = false;
+                    alive_count -= 1;
+                    for &v in tri.iter() {
+                        vertex_faces[v as usize].retain(|&it| it != f);
+                    }
+                } else {
+                    tri.iter_mut().filter(|it| **it == b).for_each(|it| *it = a);
+                    vertex_faces[a as usize].push(f);
+                }
+            }
+
+            if boundary_b {
+                let moved = std::mem::take(&mut boundary[b as usize]);
+                for &it in &moved {
+                    boundary[it as usize].retain(|&x| x != b);
+                    if it != a {
+                        boundary[it as usize].push(a);
+                    }
+                }
+                boundary[a as usize].retain(|&x| x != b);
+                boundary[a as usize].extend(moved.into_iter().filter(|&it| it != a));
+            }
+
+            versions[a as usize] += 1;
+            versions[b as usize] += 1;
+
+            for n in neighbours_of(&faces, &vertex_faces, a) {
+                push_edge(&mut heap, &positions, &quadrics, &versions, a, n);
+            }
+        }
+
+        // compact the surviving vertices and faces
+        let mut remap = vec![u32::MAX; positions.len()];
+        let mut compact_positions = vec![];
+        let compact_faces: Vec<_> = faces
+            .iter()
+            .zip(face_alive)
+            .filter(|(_, alive)| *alive)
+            .map(|((tri, tex_coords), _)| {
+                let tri = tri.map(|v| {
+                    if remap[v as usize] == u32::MAX {
+                        remap[v as usize] = compact_positions.len() as u32;
+                        compact_positions.push(positions[v as usize]);
+                    }
+                    remap[v as usize]
+                });
+                (tri, *tex_coords)
+            })
+            .collect();
+
+        let mut result = Self::from_faces(compact_positions, &compact_faces);
+        result.name = self.name.clone();
+        result.material = self.material;
+        result
+    }
+
+    // Splits vertices again where corners disagree on texture coordinates,
+    // normals are smooth across those seams
+    pub fn to_mesh(&self) -> Mesh {
+        let mut normals = vec![Vec3::ZERO; self.positions.len()];
+        for f in 0..self.face_count() {
+            let tri = self.face(f);
+            let [a, b, c] = tri.map(|i| self.positions[i as usize]);
+            let n = (b - a).cross(c - a);
+            for v in tri {
+                normals[v as usize] += n;
+            }
+        }
+
+        let mut corners: HashMap<(u32, [u32; 2]), u32> = HashMap::new();
+        let mut mesh_positions = vec![];
+        let mut mesh_normals = vec![];
+        let mut mesh_tex_coords = vec![];
+
+        let polygons = (0..self.face_count())
+            .map(|f| {
+                (0..3)
+                    .map(|k| {
+                        let half_edge = &self.half_edges[3 * f + k];
+                        let v = half_edge.origin;
+                        let key = (v, half_edge.tex_coord.to_array().map(f32::to_bits));
+                        *corners.entry(key).or_insert_with(|| {
+                            mesh_positions.push(self.positions[v as usize]);
+                            mesh_normals.push(normals[v as usize].normalize_or_zero());
+                            mesh_tex_coords.push(half_edge.tex_coord);
+                            mesh_positions.len() as u32 - 1
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut mesh = Mesh::new(
+            self.name.clone(),
+            mesh_positions,
+            mesh_normals,
+            mesh_tex_coords,
+            polygons,
+        );
+        mesh.material = self.material;
+        mesh
+    }
+}
+
+// Levels of detail, each with roughly half the triangles of the previous one
+pub fn generate_lods(mesh: &Mesh, levels: usize) -> Vec<Mesh> {
+    let mut current = HalfEdgeMesh::from_mesh(mesh);
+    (0..levels)
+        .map(|_| {
+            current = current.simplify(current.face_count() / 2);
+            current.to_mesh()
+        })
+        .collect()
+}
+
+// Symmetric 4x4 error quadric, stored as the upper triangle in f64
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(n: Vec3, d: f32) -> Self {
+        let [a, b, c, d] = [n.x as f64, n.y as f64, n.z as f64, d as f64];
+        #[rustfmt::skip]
+        let upper = [
+            a * a, a * b, a * c, a * d,
+            b * b, b * c, b * d,
+            c * c, c * d,
+            d * d,
+        ];
+        Self(upper)
+    }
+
+    fn error(&self, p: Vec3) -> f64 {
+        let q = &self.0;
+        let [x, y, z] = [p.x as f64, p.y as f64, p.z as f64];
+        q[0] * x * x
+            + 2. * q[1] * x * y
+            + 2. * q[2] * x * z
+            + 2. * q[3] * x
+            + q[4] * y * y
+            + 2. * q[5] * y * z
+            + 2. * q[6] * y
+            + q[7] * z * z
+            + 2. * q[8] * z
+            + q[9]
+    }
+
+    // Minimizer of the quadric, None when the system is close to singular
+    fn optimal_point(&self) -> Option<Vec3> {
+        let q = &self.0;
+        let m = DMat3::from_cols_array(&[q[0], q[1], q[2], q[1], q[4], q[5], q[2], q[5], q[7]]);
+        if m.determinant().abs() < 1e-10 {
+            return None;
+        }
+        let p = m.inverse() * -dvec3(q[3], q[6], q[8]);
+        Some(p.as_vec3())
+    }
+}
+
+impl std::ops::Add for Quadric {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        let mut result = self;
+        result += rhs;
+        result
+    }
+}
+
+impl std::ops::AddAssign for Quadric {
+    fn add_assign(&mut self, rhs: Self) {
+        for (a, b) in self.0.iter_mut().zip(rhs.0) {
+            *a += b;
+        }
+    }
+}
+
+impl std::ops::Mul<f64> for Quadric {
+    type Output = Self;
+
+    fn mul(self, rhs: f64) -> Self {
+        Self(self.0.map(|it| it * rhs))
+    }
+}
+
+struct Collapse {
+    cost: f64,
+    a: u32,
+    b: u32,
+    target: Vec3,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost.total_cmp(&other.cost) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cost.total_cmp(&other.cost)
+    }
+}
@@ -0,0 +1,335 @@
+use std::f32::consts::PI;
+
+use glam::*;
+
+use crate::texture::Texture;
+
+const IRRADIANCE_SIZE: UVec2 = uvec2(32, 16);
+// the irradiance is integrated over the first source level at most this wide
+const IRRADIANCE_SOURCE_WIDTH: usize = 64;
+const SPECULAR_SIZE: UVec2 = uvec2(128, 64);
+// roughness 0 to 1 in even steps, each level half the size of the previous
+const SPECULAR_LEVELS: usize = 6;
+const SPECULAR_SAMPLES: u32 = 64;
+const BRDF_LUT_SIZE: usize = 32;
+const BRDF_SAMPLES: u32 = 128;
+
+// Radiance over all directions in the equirectangular layout: u goes around
+// the y axis starting at -z, v from +y at the top to -y at the bottom
+#[derive(Clone)]
+struct EquirectMap {
+    w: usize,
+    h: usize,
+    data: Vec<Vec3>,
+}
+
+impl EquirectMap {
+    fn from_fn(w: usize, h: usize, f: impl Fn(Vec3) -> Vec3) -> Self {
+        let mut data = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                data.push(f(texel_direction(x, y, w, h)));
+            }
+        }
+        Self { w, h, data }
+    }
+
+    // Half the size with a 2x2 box filter
+    fn downsample(&self) -> Self {
+        let (w, h) = ((self.w / 2).max(1), (self.h / 2).max(1));
+        let mut data = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let at = |dx: usize, dy: usize| {
+                    let sx = (x * 2 + dx).min(self.w - 1);
+                    let sy = (y * 2 + dy).min(self.h - 1);
+                    self.data[sy * self.w + sx]
+                };
+                data.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) * 0.25);
+            }
+        }
+        Self { w, h, data }
+    }
+
+    // Bilinear, wrapping around horizontally
+    fn sample(&self, direction: Vec3) -> Vec3 {
+        let uv = direction_to_uv(direction);
+        let x = uv.x * self.w as f32 - 0.5;
+        let y = (uv.y * self.h as f32 - 0.5).clamp(0., (self.h - 1) as f32);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let at = |x: isize, y: usize| {
+            let x = x.rem_euclid(self.w as isize) as usize;
+            self.data[y.min(self.h - 1) * self.w + x]
+        };
+        let (x0, y0) = (x0 as isize, y0 as usize);
+
+        let top = at(x0, y0).lerp(at(x0 + 1, y0), fx);
+        let bottom = at(x0, y0 + 1).lerp(at(x0 + 1, y0 + 1), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+fn direction_to_uv(direction: Vec3) -> Vec2 {
+    let d = direction.normalize();
+    vec2(
+        0.5 + d.x.atan2(-d.z) / (2. * PI),
+        d.y.clamp(-1., 1.).acos() / PI,
+    )
+}
+
+fn texel_direction(x: usize, y: usize, w: usize, h: usize) -> Vec3 {
+    let phi = ((x as f32 + 0.5) / w as f32 - 0.5) * 2. * PI;
+    let theta = (y as f32 + 0.5) / h as f32 * PI;
+    vec3(
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        -theta.sin() * phi.cos(),
+    )
+}
+
+// Texels near the poles cover less of the sphere
+fn texel_solid_angle(y: usize, w: usize, h: usize) -> f32 {
+    let theta = (y as f32 + 0.5) / h as f32 * PI;
+    (2. * PI / w as f32) * (PI / h as f32) * theta.sin()
+}
+
+fn hammersley(i: u32, count: u32) -> Vec2 {
+    vec2(
+        i as f32 / count as f32,
+        i.reverse_bits() as f32 / 2f32.powi(32),
+    )
+}
+
+// Half vector around `n` distributed like the GGX lobe of `roughness`
+fn importance_sample_ggx(xi: Vec2, n: Vec3, roughness: f32) -> Vec3 {
+    let a = roughness * roughness;
+    let phi = 2. * PI * xi.x;
+    let cos_theta = ((1. - xi.y) / (1. + (a * a - 1.) * xi.y)).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+
+    let up = if n.z.abs() < 0.999 { Vec3::Z } else { Vec3::X };
+    let tangent = up.cross(n).normalize();
+    let bitangent = n.cross(tangent);
+    (tangent * phi.cos() * sin_theta + bitangent * phi.sin() * sin_theta + n * cos_theta)
+        .normalize()
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a2 = roughness.powi(4);
+    let d = n_dot_h * n_dot_h * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+// Smith-Schlick with the k of image based lighting
+fn geometry_smith_ibl(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = roughness * roughness / 2.;
+    let g1 = |n_dot_x: f32| n_dot_x / (n_dot_x * (1. - k) + k);
+    g1(n_dot_v) * g1(n_dot_l)
+}
+
+// Split-sum scale and bias applied to F0 for a given n·v and roughness
+fn integrate_brdf(n_dot_v: f32, roughness: f32) -> Vec2 {
+    let v = vec3((1. - n_dot_v * n_dot_v).sqrt(), 0., n_dot_v);
+    let mut result = Vec2::ZERO;
+
+    for i in 0..BRDF_SAMPLES {
+        let h = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), Vec3::Z, roughness);
+        let l = 2. * v.dot(h) * h - v;
+        let (n_dot_l, n_dot_h, v_dot_h) = (l.z, h.z.max(0.), v.dot(h).max(0.));
+        if n_dot_l <= 0. {
+            continue;
+        }
+
+        let g = geometry_smith_ibl(n_dot_v, n_dot_l, roughness);
+        let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+        let fc = (1. - v_dot_h).powi(5);
+        result += vec2((1. - fc) * g_vis, fc * g_vis);
+    }
+
+    result / BRDF_SAMPLES as f32
+}
+
+// Image based lighting from an equirectangular environment, prefiltered
+// once with the split-sum approximation. Directions are in view space, like
+// the lights, and turned back to world space with the view of `set_view`.
+pub struct Environment {
+    // the source and its box filtered levels, the skybox reads the first
+    radiance: Vec<EquirectMap>,
+    // cosine weighted average of the radiance around a normal, the diffuse
+    // light is the albedo times this
+    irradiance: EquirectMap,
+    // radiance convolved with the GGX lobe of increasing roughness
+    specular: Vec<EquirectMap>,
+    // n·v along x, roughness along y
+    brdf_lut: Vec<Vec2>,
+    // the inverse rotation of the view
+    view_to_world: Mat3,
+}
+
+impl Environment {
+    // Loads an equirectangular image, usually an `.hdr`
+    pub fn new(path: &str) -> Self {
+        Self::from_texture(&Texture::new(path))
+    }
+
+    pub fn from_texture(texture: &Texture) -> Self {
+        let (w, h) = (texture.width() as usize, texture.height() as usize);
+        let mut data = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                data.push(texture.get_color(x as f32, y as f32));
+            }
+        }
+
+        let mut radiance = vec![EquirectMap { w, h, data }];
+        while radiance.last().unwrap().w > 1 {
+            let next = radiance.last().unwrap().downsample();
+            radiance.push(next);
+        }
+
+        let irradiance = Self::prefilter_irradiance(&radiance);
+        let specular = (0..SPECULAR_LEVELS)
+            .map(|level| Self::prefilter_specular(&radiance, level))
+            .collect();
+
+        let mut brdf_lut = Vec::with_capacity(BRDF_LUT_SIZE * BRDF_LUT_SIZE);
+        for y in 0..BRDF_LUT_SIZE {
+            for x in 0..BRDF_LUT_SIZE {
+                let n_dot_v = (x as f32 + 0.5) / BRDF_LUT_SIZE as f32;
+                let roughness = (y as f32 + 0.5) / BRDF_LUT_SIZE as f32;
+                brdf_lut.push(integrate_brdf(n_dot_v, roughness));
+            }
+        }
+
+        Self {
+            radiance,
+            irradiance,
+            specular,
+            brdf_lut,
+            view_to_world: Mat3::IDENTITY,
+        }
+    }
+
+    fn prefilter_irradiance(radiance: &[EquirectMap]) -> EquirectMap {
+        let source = radiance
+            .iter()
+            .find(|it| it.w <= IRRADIANCE_SOURCE_WIDTH)
+            .unwrap_or(radiance.last().unwrap());
+
+        let mut texels = Vec::with_capacity(source.data.len());
+        for y in 0..source.h {
+            let solid_angle = texel_solid_angle(y, source.w, source.h);
+            for x in 0..source.w {
+                let direction = texel_direction(x, y, source.w, source.h);
+                texels.push((direction, source.data[y * source.w + x] * solid_angle));
+            }
+        }
+
+        EquirectMap::from_fn(
+            IRRADIANCE_SIZE.x as usize,
+            IRRADIANCE_SIZE.y as usize,
+            |normal| {
+                let sum: Vec3 = texels
+                    .iter()
+                    .map(|&(direction, radiance)| radiance * normal.dot(direction).max(0.))
+                    .sum();
+                sum / PI
+            },
+        )
+    }
+
+    // Importance samples the GGX lobe around every texel, reading from the
+    // source level whose texels are about as big as the solid angle of a
+    // sample so few samples don't alias
+    fn prefilter_specular(radiance: &[EquirectMap], level: usize) -> EquirectMap {
+        let roughness = level as f32 / (SPECULAR_LEVELS - 1) as f32;
+        let w = (SPECULAR_SIZE.x as usize >> level).max(1);
+        let h = (SPECULAR_SIZE.y as usize >> level).max(1);
+
+        if level == 0 {
+            return EquirectMap::from_fn(w, h, |direction| radiance[0].sample(direction));
+        }
+
+        let source = &radiance[0];
+        let texel_solid_angle = 4. * PI / (source.w * source.h) as f32;
+        let last_level = (radiance.len() - 1) as f32;
+
+        EquirectMap::from_fn(w, h, |n| {
+            // the view is assumed to be along the normal
+            let v = n;
+            let mut sum = Vec3::ZERO;
+            let mut weight = 0.;
+
+            for i in 0..SPECULAR_SAMPLES {
+                let h = importance_sample_ggx(hammersley(i, SPECULAR_SAMPLES), n, roughness);
+                let l = 2. * v.dot(h) * h - v;
+                let n_dot_l = n.dot(l);
+                if n_dot_l <= 0. {
+                    continue;
+                }
+
+                let n_dot_h = n.dot(h).max(0.);
+                let pdf = distribution_ggx(n_dot_h, roughness) / 4. + 1e-4;
+                let sample_solid_angle = 1. / (SPECULAR_SAMPLES as f32 * pdf);
+                let mip = (0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.)
+                    .clamp(0., last_level);
+
+                let lower = mip.floor() as usize;
+                let upper = mip.ceil() as usize;
+                let color = radiance[lower]
+                    .sample(l)
+                    .lerp(radiance[upper].sample(l), mip - lower as f32);
+                sum += color * n_dot_l;
+                weight += n_dot_l;
+            }
+
+            sum / weight.max(1e-4)
+        })
+    }
+
+    // The view matrix the directions are in, only its rotation is used
+    pub fn set_view(&mut self, view: Mat4) {
+        self.view_to_world = Mat3::from_mat4(view).transpose();
+    }
+
+    // What the skybox shows in `direction`
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        self.radiance[0].sample(self.view_to_world * direction)
+    }
+
+    pub fn irradiance(&self, normal: Vec3) -> Vec3 {
+        self.irradiance.sample(self.view_to_world * normal)
+    }
+
+    // Prefiltered radiance around the reflected `direction`, blended between
+    // the two closest roughness levels
+    pub fn specular(&self, direction: Vec3, roughness: f32) -> Vec3 {
+        let level = roughness.clamp(0., 1.) * (SPECULAR_LEVELS - 1) as f32;
+        let lower = level.floor() as usize;
+        let upper = level.ceil() as usize;
+        let direction = self.view_to_world * direction;
+        self.specular[lower]
+            .sample(direction)
+            .lerp(self.specular[upper].sample(direction), level - lower as f32)
+    }
+
+    // Scale and bias to F0 of the split-sum approximation
+    pub fn brdf(&self, n_dot_v: f32, roughness: f32) -> Vec2 {
+        let size = BRDF_LUT_SIZE as f32;
+        let x = (n_dot_v.clamp(0., 1.) * size - 0.5).clamp(0., size - 1.);
+        let y = (roughness.clamp(0., 1.) * size - 0.5).clamp(0., size - 1.);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = (
+            (x0 + 1).min(BRDF_LUT_SIZE - 1),
+            (y0 + 1).min(BRDF_LUT_SIZE - 1),
+        );
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let at = |x: usize, y: usize| self.brdf_lut[y * BRDF_LUT_SIZE + x];
+        let top = at(x0, y0).lerp(at(x1, y0), fx);
+        let bottom = at(x0, y1).lerp(at(x1, y1), fx);
+        top.lerp(bottom, fy)
+    }
+}
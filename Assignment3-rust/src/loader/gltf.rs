@@ -0,0 +1,177 @@
+use ::gltf::{image::Format, mesh::Mode};
+use glam::*;
+
+use crate::{
+    mesh::{Material, Mesh},
+    texture::Texture,
+};
+
+fn texture_from_image(data: &::gltf::image::Data) -> Texture {
+    let pixel_count = (data.width * data.height) as usize;
+
+    // (channels, bytes per channel)
+    let (channels, size) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+
+    let channel = |pixel: usize, c: usize| -> f32 {
+        // grayscale images replicate red, missing channels read as 0
+        let c = if channels < 3 { if c < channels { c } else { 0 } } else { c };
+        let offset = (pixel * channels + c) * size;
+        let raw = &data.pixels[offset..offset + size];
+        match size {
+            1 => raw[0] as f32 / 255.,
+            2 => u16::from_le_bytes([raw[0], raw[1]]) as f32 / 65535.,
+            _ => f32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+        }
+    };
+
+    let pixels = (0..pixel_count)
+        .flat_map(|pixel| [channel(pixel, 0), channel(pixel, 1), channel(pixel, 2)])
+        .collect();
+
+    Texture::from_raw(data.width, data.height, pixels)
+}
+
+fn material_from_gltf(material: &::gltf::Material, images: &[::gltf::image::Data]) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let texture = |texture: ::gltf::Texture| texture_from_image(&images[texture.source().index()]);
+
+    Material {
+        name: material.name().unwrap_or_default().to_string(),
+        base_color: Vec4::from(pbr.base_color_factor()),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        emissive: Vec3::from(material.emissive_factor()),
+
+        base_color_texture: pbr.base_color_texture().map(|it| texture(it.texture())),
+        metallic_roughness_texture: pbr
+            .metallic_roughness_texture()
+            .map(|it| texture(it.texture())),
+        normal_texture: material.normal_texture().map(|it| texture(it.texture())),
+        occlusion_texture: material.occlusion_texture().map(|it| texture(it.texture())),
+        emissive_texture: material.emissive_texture().map(|it| texture(it.texture())),
+    }
+}
+
+fn mesh_from_primitive(
+    name: String,
+    primitive: &::gltf::Primitive,
+    buffers: &[::gltf::buffer::Data],
+    transform: Mat4,
+) -> Option<Mesh> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+    let positions: Vec<Vec3> = reader
+        .read_positions()?
+        .map(|p| transform.transform_point3(Vec3::from(p)))
+        .collect();
+
+    let normal_matrix = Mat3::from_mat4(transform).inverse().transpose();
+    let normals: Vec<Vec3> = reader
+        .read_normals()
+        .map(|it| it.map(|n| (normal_matrix * Vec3::from(n)).normalize_or_zero()).collect())
+        .unwrap_or_default();
+
+    // glTF puts the uv origin at the top left, like the texture lookup
+    let tex_coords: Vec<Vec2> = reader
+        .read_tex_coords(0)
+        .map(|it| it.into_f32().map(Vec2::from).collect())
+        .unwrap_or_default();
+
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+
+    let polygons: Vec<Vec<u32>> = match primitive.mode() {
+        Mode::Triangles => indices.chunks_exact(3).map(|it| it.to_vec()).collect(),
+        Mode::TriangleStrip => (0..indices.len().saturating_sub(2))
+            .map(|i| {
+                // every other triangle in a strip has flipped winding
+                if i % 2 == 0 {
+                    vec![indices[i], indices[i + 1], indices[i + 2]]
+                } else {
+                    vec![indices[i + 1], indices[i], indices[i + 2]]
+                }
+            })
+            .collect(),
+        Mode::TriangleFan => (1..indices.len().saturating_sub(1))
+            .map(|i| vec![indices[0], indices[i], indices[i + 1]])
+            .collect(),
+        // points and lines are not rasterized
+        _ => return None,
+    };
+
+    let mut mesh = Mesh::new(name, positions, normals, tex_coords, polygons);
+    mesh.material = primitive.material().index();
+    Some(mesh)
+}
+
+fn collect_node_meshes(
+    node: &::gltf::Node,
+    parent: Mat4,
+    buffers: &[::gltf::buffer::Data],
+    meshes: &mut Vec<Mesh>,
+) {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            let name = format!(
+                "{}.{}",
+                mesh.name().unwrap_or_default(),
+                primitive.index()
+            );
+            meshes.extend(mesh_from_primitive(name, &primitive, buffers, transform));
+        }
+    }
+
+    for child in node.children() {
+        collect_node_meshes(&child, transform, buffers, meshes);
+    }
+}
+
+// Reads .gltf (with external or data uri buffers) and .glb files. Node
+// transforms of the default scene are baked into the vertices, materials keep
+// their PBR factors and embedded textures.
+pub fn load_gltf(path: &str) -> (Vec<Mesh>, Vec<Material>) {
+    let (document, buffers, images) = ::gltf::import(path).unwrap();
+
+    let materials = document
+        .materials()
+        .map(|it| material_from_gltf(&it, &images))
+        .collect();
+
+    let mut meshes = vec![];
+    match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(scene) => {
+            for node in scene.nodes() {
+                collect_node_meshes(&node, Mat4::IDENTITY, &buffers, &mut meshes);
+            }
+        }
+        None => {
+            for mesh in document.meshes() {
+                for primitive in mesh.primitives() {
+                    let name = format!(
+                        "{}.{}",
+                        mesh.name().unwrap_or_default(),
+                        primitive.index()
+                    );
+                    meshes.extend(mesh_from_primitive(name, &primitive, &buffers, Mat4::IDENTITY));
+                }
+            }
+        }
+    }
+
+    (meshes, materials)
+}
@@ -0,0 +1,185 @@
+mod gltf;
+mod ply;
+mod stl;
+
+use std::path::Path;
+
+use crate::{
+    mesh::{Material, Mesh},
+    rasterizer::Rasterizer,
+    texture::Texture,
+    triangle::Triangle,
+};
+use glam::*;
+
+pub use self::gltf::load_gltf;
+pub use self::ply::load_ply;
+pub use self::stl::load_stl;
+
+// Picks the loader from the file extension
+pub fn load_meshes(path: &str) -> (Vec<Mesh>, Vec<Material>) {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|it| it.to_str())
+        .map(|it| it.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("obj") => load_obj_meshes(path),
+        Some("ply") => load_ply(path),
+        Some("stl") => load_stl(path),
+        Some("gltf" | "glb") => load_gltf(path),
+        _ => panic!("Unsupported mesh format: {path}"),
+    }
+}
+
+// Texture paths in MTL files are relative to the OBJ, some exporters write
+// absolute paths from the authoring machine so retry with the bare file name
+fn load_material_texture(dir: &Path, texture_path: &str) -> Option<Texture> {
+    let file_name = texture_path.rsplit(['/', '\\']).next().unwrap_or(texture_path);
+
+    [dir.join(texture_path), dir.join(file_name)]
+        .iter()
+        .find_map(|path| image::open(path).ok())
+        .map(|image| Texture::from_image(image.into_rgb8()))
+}
+
+fn material_from_mtl(dir: &Path, material: &tobj::Material) -> Material {
+    let diffuse = material.diffuse.unwrap_or([1., 1., 1.]);
+    let load = |texture: &Option<String>| {
+        texture
+            .as_ref()
+            .and_then(|it| load_material_texture(dir, it))
+    };
+    // PBR extensions written by `exporter::save_obj`
+    let param = |key: &str| material.unknown_param.get(key);
+    let factor = |key: &str| param(key).and_then(|it| it.trim().parse::<f32>().ok());
+
+    Material {
+        name: material.name.clone(),
+        base_color: vec4(
+            diffuse[0],
+            diffuse[1],
+            diffuse[2],
+            material.dissolve.unwrap_or(1.),
+        ),
+        metallic: factor("Pm").unwrap_or(0.),
+        // the usual Blinn-Phong exponent to GGX roughness mapping
+        roughness: factor("Pr").unwrap_or_else(|| {
+            material
+                .shininess
+                .map(|ns| (2. / (ns + 2.)).sqrt())
+                .unwrap_or(1.)
+        }),
+        emissive: material.emissive.map_or(Vec3::ZERO, Vec3::from),
+
+        base_color_texture: load(&material.diffuse_texture),
+        metallic_roughness_texture: load(&param("map_Pmr").cloned()),
+        normal_texture: load(&material.normal_texture),
+        occlusion_texture: load(&param("map_ao").cloned()),
+        emissive_texture: load(&param("map_Ke").cloned()),
+    }
+}
+
+// Like `tobj::GPU_LOAD_OPTIONS` but keeps the original faces, quads and
+// n-gons are triangulated by `mesh::triangulate_polygon` instead
+const POLYGON_LOAD_OPTIONS: tobj::LoadOptions = tobj::LoadOptions {
+    single_index: true,
+    triangulate: false,
+    ignore_points: true,
+    ignore_lines: true,
+};
+
+pub fn load_obj_meshes(obj_path: &str) -> (Vec<Mesh>, Vec<Material>) {
+    let (models, materials) = tobj::load_obj(obj_path, &POLYGON_LOAD_OPTIONS).unwrap();
+
+    let dir = Path::new(obj_path).parent().unwrap_or(Path::new("."));
+    let materials = match materials {
+        Ok(materials) => materials
+            .iter()
+            .map(|it| material_from_mtl(dir, it))
+            .collect(),
+        Err(_) => vec![],
+    };
+
+    let meshes = models
+        .into_iter()
+        .map(|model| {
+            let mesh = model.mesh;
+
+            // empty arities means every face is a triangle
+            let arities = if mesh.face_arities.is_empty() {
+                vec![3; mesh.indices.len() / 3]
+            } else {
+                mesh.face_arities
+            };
+
+            let mut offset = 0;
+            let polygons = arities
+                .iter()
+                .map(|&arity| {
+                    let polygon = mesh.indices[offset..offset + arity as usize].to_vec();
+                    offset += arity as usize;
+                    polygon
+                })
+                .collect::<Vec<_>>();
+
+            let mut result = Mesh::new(
+                model.name,
+                mesh.positions
+                    .chunks(3)
+                    .map(|v| vec3(v[0], v[1], v[2]))
+                    .collect(),
+                mesh.normals
+                    .chunks(3)
+                    .map(|v| vec3(v[0], v[1], v[2]))
+                    .collect(),
+                mesh.texcoords
+                    .chunks(2)
+                    .map(|v| vec2(v[0], v[1]))
+                    .collect(),
+                polygons,
+            );
+            result.material = mesh.material_id;
+            result
+        })
+        .collect::<_>();
+
+    (meshes, materials)
+}
+
+pub fn load_obj(obj_path: &str) -> Vec<Vec<Triangle>> {
+    load_obj_meshes(obj_path)
+        .0
+        .iter()
+        .map(|mesh| mesh.triangles())
+        .collect::<_>()
+}
+
+impl Rasterizer {
+    pub fn load_obj(&mut self, obj_path: &str) {
+        // let obj_path = "models/spot/spot_triangulated_good.obj";
+        // > positions: (9675) vec![0.31728
+        // > vertex_color: (0) vec! []
+        // > normals: (9675) vec![0.57446701
+        // > texcoords: (6450) vec! [0.80037.
+        // > indices: (17568) vec![0, 1, 2,
+        // > face arities: (0) vec![]
+        // > texcoord_indices: (0) vec! []
+        // > normal_indices: (0) vec![]
+
+        for mesh in load_obj_meshes(obj_path).0 {
+            let ind_id = self.load_indices(mesh.indices);
+            let pos_id = self.load_positions(mesh.positions);
+
+            let normals = mesh
+                .normals
+                .iter()
+                .map(|v| v.extend(1.0))
+                .collect::<Vec<_>>();
+
+            let normal_id = self.load_normals(normals);
+
+            let texcoords_id = self.load_tex_coords(mesh.tex_coords);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::str::SplitAsciiWhitespace;
+
+use glam::*;
+
+use crate::mesh::{Material, Mesh};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    // name, type of the length prefix, type of the items
+    List(String, Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+fn parse_scalar(name: &str) -> Scalar {
+    match name {
+        "char" | "int8" => Scalar::I8,
+        "uchar" | "uint8" => Scalar::U8,
+        "short" | "int16" => Scalar::I16,
+        "ushort" | "uint16" => Scalar::U16,
+        "int" | "int32" => Scalar::I32,
+        "uint" | "uint32" => Scalar::U32,
+        "float" | "float32" => Scalar::F32,
+        "double" | "float64" => Scalar::F64,
+        _ => panic!("Unknown PLY property type: {name}"),
+    }
+}
+
+fn parse_header(header: &str) -> (Format, Vec<Element>) {
+    let mut lines = header.lines().map(|it| it.trim());
+
+    if lines.next() != Some("ply") {
+        panic!("Missing PLY magic number");
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+
+    for line in lines {
+        let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+        match tokens.as_slice() {
+            ["format", kind, _version] => {
+                format = Some(match *kind {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => panic!("Unknown PLY format: {kind}"),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().unwrap(),
+                properties: vec![],
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .expect("PLY property before any element")
+                .properties
+                .push(Property::List(
+                    name.to_string(),
+                    parse_scalar(count),
+                    parse_scalar(item),
+                )),
+            ["property", ty, name] => elements
+                .last_mut()
+                .expect("PLY property before any element")
+                .properties
+                .push(Property::Scalar(name.to_string(), parse_scalar(ty))),
+            _ => {}
+        }
+    }
+
+    (format.expect("Missing PLY format line"), elements)
+}
+
+struct BodyReader<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    cursor: usize,
+    tokens: Option<SplitAsciiWhitespace<'a>>,
+}
+
+impl<'a> BodyReader<'a> {
+    fn new(format: Format, bytes: &'a [u8]) -> Self {
+        let tokens = match format {
+            Format::Ascii => Some(
+                std::str::from_utf8(bytes)
+                    .expect("ASCII PLY body is not valid UTF-8")
+                    .split_ascii_whitespace(),
+            ),
+            _ => None,
+        };
+
+        Self {
+            format,
+            bytes,
+            cursor: 0,
+            tokens,
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut raw: [u8; N] = self.bytes[self.cursor..self.cursor + N]
+            .try_into()
+            .expect("Unexpected end of PLY body");
+        self.cursor += N;
+        if self.format == Format::BinaryBigEndian {
+            raw.reverse();
+        }
+        raw
+    }
+
+    fn read(&mut self, ty: Scalar) -> f64 {
+        if let Some(tokens) = &mut self.tokens {
+            return tokens
+                .next()
+                .expect("Unexpected end of PLY body")
+                .parse()
+                .expect("Malformed number in PLY body");
+        }
+
+        // bytes are already little endian after `take`
+        match ty {
+            Scalar::I8 => i8::from_le_bytes(self.take()) as f64,
+            Scalar::U8 => u8::from_le_bytes(self.take()) as f64,
+            Scalar::I16 => i16::from_le_bytes(self.take()) as f64,
+            Scalar::U16 => u16::from_le_bytes(self.take()) as f64,
+            Scalar::I32 => i32::from_le_bytes(self.take()) as f64,
+            Scalar::U32 => u32::from_le_bytes(self.take()) as f64,
+            Scalar::F32 => f32::from_le_bytes(self.take()) as f64,
+            Scalar::F64 => f64::from_le_bytes(self.take()),
+        }
+    }
+}
+
+// Reads ASCII and binary PLY files. Only the `vertex` and `face` elements are
+// used, any other element is parsed and skipped.
+pub fn load_ply(path: &str) -> (Vec<Mesh>, Vec<Material>) {
+    let bytes = std::fs::read(path).unwrap();
+
+    let marker = b"end_header";
+    let header_end = bytes
+        .windows(marker.len())
+        .position(|it| it == marker)
+        .expect("Missing PLY end_header");
+    // the body starts after the line break following end_header
+    let body_start = bytes[header_end..]
+        .iter()
+        .position(|&it| it == b'\n')
+        .map(|it| header_end + it + 1)
+        .unwrap_or(bytes.len());
+
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+    let (format, elements) = parse_header(&header);
+    let mut reader = BodyReader::new(format, &bytes[body_start..]);
+
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut tex_coords = vec![];
+    let mut polygons = vec![];
+
+    for element in &elements {
+        for _ in 0..element.count {
+            let mut position = Vec3::ZERO;
+            let mut normal = Vec3::ZERO;
+            let mut tex_coord = Vec2::ZERO;
+            let (mut has_normal, mut has_tex_coord) = (false, false);
+
+            for property in &element.properties {
+                match property {
+                    Property::Scalar(name, ty) => {
+                        let value = reader.read(*ty) as f32;
+                        match name.as_str() {
+                            "x" => position.x = value,
+                            "y" => position.y = value,
+                            "z" => position.z = value,
+                            "nx" => (normal.x, has_normal) = (value, true),
+                            "ny" => (normal.y, has_normal) = (value, true),
+                            "nz" => (normal.z, has_normal) = (value, true),
+                            "u" | "s" | "texture_u" => (tex_coord.x, has_tex_coord) = (value, true),
+                            "v" | "t" | "texture_v" => (tex_coord.y, has_tex_coord) = (value, true),
+                            _ => {}
+                        }
+                    }
+                    Property::List(name, count, item) => {
+                        let count = reader.read(*count) as usize;
+                        let list: Vec<u32> = (0..count).map(|_| reader.read(*item) as u32).collect();
+                        if element.name == "face"
+                            && (name == "vertex_indices" || name == "vertex_index")
+                        {
+                            polygons.push(list);
+                        }
+                    }
+                }
+            }
+
+            if element.name == "vertex" {
+                positions.push(position);
+                if has_normal {
+                    normals.push(normal);
+                }
+                if has_tex_coord {
+                    tex_coords.push(tex_coord);
+                }
+            }
+        }
+    }
+
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .map(|it| it.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    (
+        vec![Mesh::new(name, positions, normals, tex_coords, polygons)],
+        vec![],
+    )
+}
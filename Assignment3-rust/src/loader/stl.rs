@@ -0,0 +1,35 @@
+use std::fs::File;
+
+use glam::*;
+
+use crate::mesh::{Material, Mesh};
+
+// Reads ASCII and binary STL files. STL only stores flat facet normals and no
+// texture coordinates, so vertices are welded and smooth normals are
+// recomputed by `Mesh::new`.
+pub fn load_stl(path: &str) -> (Vec<Mesh>, Vec<Material>) {
+    let mut file = File::open(path).unwrap();
+    let stl = stl_io::read_stl(&mut file).unwrap();
+
+    let positions = stl
+        .vertices
+        .iter()
+        .map(|v| vec3(v[0], v[1], v[2]))
+        .collect();
+
+    let polygons = stl
+        .faces
+        .iter()
+        .map(|face| face.vertices.iter().map(|&i| i as u32).collect())
+        .collect();
+
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .map(|it| it.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    (
+        vec![Mesh::new(name, positions, vec![], vec![], polygons)],
+        vec![],
+    )
+}
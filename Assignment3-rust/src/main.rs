@@ -1,22 +1,43 @@
 mod rasterizer;
+mod abuffer;
+mod clipping;
 mod loader;
+mod mesh;
+mod halfedge;
+mod ibl;
+mod exporter;
+mod postprocess;
+mod shadow;
+mod ssao;
 mod transform;
 mod triangle;
 mod utils;
 mod texture;
 mod shader;
-use std::env;
+use std::{env, path::Path};
 
 use crate::rasterizer::Rasterizer;
 use crate::transform::*;
 
 use core_graphics::geometry::CGSize;
-use glam::{uvec3, vec3, vec4};
-use loader::load_obj;
+use glam::{uvec3, vec3, vec4, Mat4, Vec2, Vec3, Vec4};
+use ibl::Environment;
+use loader::load_meshes;
 use metal::{Device, MTLPixelFormat, MTLResourceOptions};
-use rasterizer::{BufferKind, PrimitiveKind};
-use shader::{vertex_shader, phong_fragment_shader};
+use rasterizer::{
+    BlendMode, BufferKind, CompareFunc, CullMode, PostAntialiasing, PrimitiveKind, WireframeMode,
+};
+use halfedge::generate_lods;
+use mesh::{Material, Mesh};
+use postprocess::ToneMapping;
+use shader::{
+    normal_map_fragment_shader, pbr_fragment_shader, phong_fragment_shader,
+    texture_fragment_shader, vertex_shader, FragmentShaderFn, Light, LightKind,
+};
+use shadow::ShadowMap;
+use ssao::Ssao;
 use texture::Texture;
+use triangle::Triangle;
 use utils::{image::save_image, render::*, shader_types::TexturedVertex};
 use winit::{
     event::{VirtualKeyCode, WindowEvent, ElementState},
@@ -47,6 +68,185 @@ const DELTA_ANGLE: f32 = 1.;
 const ASSET_PATH: &str = "models/spot/";
 const MODEL_PATH: &str = "models/spot/spot_triangulated_good.obj";
 const TEXTURE_PATH: &str = "models/spot/spot_texture.png";
+// turned into the normal map of the model
+const HEIGHT_MAP_PATH: &str = "models/spot/hmap.jpg";
+// optional equirectangular HDR for image based lighting and the skybox
+const ENVIRONMENT_PATH: &str = "models/environment.hdr";
+
+const SHADOW_MAP_SIZE: usize = 1024;
+// simplified versions of the model the L key cycles through
+const LOD_LEVELS: usize = 3;
+
+// In view space, the model sits at `target`
+fn scene_lights(target: Vec3) -> Vec<Light> {
+    let key_position = vec3(20., 20., 20.);
+    vec![
+        Light::spot(key_position, target - key_position, vec3(500., 500., 500.), 3., 5.),
+        Light::point(vec3(-20., 20., 0.), vec3(500., 500., 500.)),
+    ]
+}
+
+// The alpha of the material color is the MTL `d` transparency
+fn mesh_triangles(meshes: &[Mesh], materials: &[Material]) -> Vec<Vec<Triangle>> {
+    meshes
+        .iter()
+        .map(|mesh| {
+            let color = mesh.material.map_or(Vec4::ONE, |i| materials[i].base_color);
+            mesh.triangles_with_color(color)
+        })
+        .collect()
+}
+
+// The material of every mesh, `fallback` for the ones without their own
+fn mesh_materials(meshes: &[Mesh], materials: &[Material], fallback: &Material) -> Vec<Material> {
+    meshes
+        .iter()
+        .map(|mesh| mesh.material.map_or(fallback, |i| &materials[i]).clone())
+        .collect()
+}
+
+// Lowest height of the meshes once placed by `model`, so the ground stays
+// right under the feet of the model while it rotates
+fn lowest_point(meshes: &[Mesh], model: Mat4) -> f32 {
+    meshes
+        .iter()
+        .flat_map(|mesh| mesh.positions.iter())
+        .fold(f32::INFINITY, |lowest, &it| lowest.min(model.transform_point3(it).y))
+}
+
+// A square of `2 * half_size` at height `y`, facing up
+fn ground_plane(y: f32, half_size: f32, color: Vec4) -> Vec<Triangle> {
+    let corners = [
+        vec3(-1., 0., -1.),
+        vec3(-1., 0., 1.),
+        vec3(1., 0., 1.),
+        vec3(1., 0., -1.),
+    ]
+    .map(|it| (it * half_size + vec3(0., y, 0.)).extend(1.));
+
+    [[0, 1, 2], [0, 2, 3]]
+        .map(|face| Triangle {
+            v: face.map(|i| corners[i]),
+            color: [color; 3],
+            tex_coords: [Vec2::ZERO; 3],
+            normal: [Vec3::Y; 3],
+            tangent: [vec4(1., 0., 0., 1.); 3],
+        })
+        .to_vec()
+}
+
+// Renders the meshes from every light that has a position, the ground only
+// receives shadows
+fn render_shadow_maps(
+    lights: &[Light],
+    view: Mat4,
+    model: Mat4,
+    triangle_lists: &[Vec<Triangle>],
+) -> Vec<ShadowMap> {
+    let target = view.transform_point3(Vec3::ZERO);
+
+    lights
+        .iter()
+        .enumerate()
+        .filter(|(_, light)| !matches!(light.kind, LightKind::Directional { .. }))
+        .map(|(index, light)| {
+            let mut shadow_map =
+                ShadowMap::new(index, light.position, target, 15., SHADOW_MAP_SIZE);
+            let mut r = shadow_map.rasterizer(view);
+            r.set_model(model);
+            for triangles in triangle_lists {
+                r.draw_triangle_list(triangles);
+            }
+            shadow_map.capture(&r);
+            shadow_map
+        })
+        .collect()
+}
+
+// The fragment shaders of a frame. The ground has no texture, so it can only
+// share the shaders that take everything from the material.
+#[derive(Clone, Copy)]
+struct SceneShaders {
+    meshes: FragmentShaderFn,
+    ground: FragmentShaderFn,
+}
+
+// The ground sits at the origin with a plain material of its vertex color
+fn draw_ground(r: &mut Rasterizer, ground: &Vec<Triangle>, shader: FragmentShaderFn) {
+    r.set_fragment_shader(shader);
+    r.set_material(Material {
+        name: String::from("ground"),
+        base_color: ground[0].color[0],
+        metallic: 0.,
+        roughness: 0.9,
+        ..Default::default()
+    });
+    r.set_model(Mat4::IDENTITY);
+    r.draw_triangle_list(ground);
+}
+
+// Opaque meshes first, then the skybox behind them and the transparent ones
+// blended on top without writing depth. `materials` has the material of
+// every triangle list.
+fn draw_meshes(
+    r: &mut Rasterizer,
+    triangle_lists: &[Vec<Triangle>],
+    materials: &[Material],
+    shader: FragmentShaderFn,
+) {
+    let is_transparent = |triangles: &Vec<Triangle>| triangles.iter().any(|t| t.color[0].w < 1.);
+    let meshes = || triangle_lists.iter().zip(materials);
+
+    r.set_fragment_shader(shader);
+    for (triangles, material) in meshes().filter(|(it, _)| !is_transparent(it)) {
+        r.set_material(material.clone());
+        r.draw_triangle_list(triangles);
+    }
+    r.draw_skybox();
+
+    r.set_blend_mode(BlendMode::Alpha);
+    r.set_depth_write(false);
+    for (triangles, material) in meshes().filter(|(it, _)| is_transparent(it)) {
+        r.set_material(material.clone());
+        r.draw_triangle_list(triangles);
+    }
+    r.set_blend_mode(BlendMode::Replace);
+    r.set_depth_write(true);
+}
+
+fn draw_scene(
+    r: &mut Rasterizer,
+    ground: &Vec<Triangle>,
+    triangle_lists: &[Vec<Triangle>],
+    materials: &[Material],
+    model: Mat4,
+    shaders: SceneShaders,
+) {
+    draw_ground(r, ground, shaders.ground);
+    r.set_model(model);
+    draw_meshes(r, triangle_lists, materials, shaders.meshes);
+}
+
+// With SSAO the scene is drawn twice, first only its depth for the occlusion
+// and then the colors on top of that depth
+fn draw_scene_with_ssao(
+    r: &mut Rasterizer,
+    ground: &Vec<Triangle>,
+    triangle_lists: &[Vec<Triangle>],
+    materials: &[Material],
+    model: Mat4,
+    shaders: SceneShaders,
+    ssao: &Ssao,
+) {
+    r.set_color_write(false);
+    draw_scene(r, ground, triangle_lists, materials, model, shaders);
+    r.compute_ambient_occlusion(ssao);
+
+    r.set_color_write(true);
+    r.set_depth_func(CompareFunc::LessEqual);
+    draw_scene(r, ground, triangle_lists, materials, model, shaders);
+    r.set_depth_func(CompareFunc::Less);
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -56,20 +256,71 @@ fn main() {
     let mut r = Rasterizer::new(INITIAL_WINDOW_WIDTH as _, INITIAL_WINDOW_HEIGHT as _, 2);
     
     
-    let triangle_lists = loader::load_obj(MODEL_PATH);
+    let (meshes, materials) = load_meshes(MODEL_PATH);
+    // level 0 is the model as loaded, every level after it has about half
+    // the triangles of the one before
+    let lods: Vec<Vec<Mesh>> = meshes.iter().map(|it| generate_lods(it, LOD_LEVELS)).collect();
+    let mut levels = vec![meshes.clone()];
+    levels.extend((0..LOD_LEVELS).map(|k| lods.iter().map(|it| it[k].clone()).collect()));
+    let mut lod = 0;
+    let mut triangle_lists = mesh_triangles(&levels[lod], &materials);
+    let mut angle = 0f32;
+    let ground_color = vec4(0.5, 0.5, 0.5, 1.);
+    let mut ground = ground_plane(
+        lowest_point(&levels[0], get_model_matrix(angle)),
+        4.,
+        ground_color,
+    );
+    let mut wireframe: Option<WireframeMode> = None;
     r.set_texture(Texture::new(TEXTURE_PATH));
+    r.set_normal_map(Texture::new(HEIGHT_MAP_PATH).normal_map_from_height(5.));
     r.set_vertex_shader(vertex_shader);
-    r.set_fragment_shader(phong_fragment_shader);
+    r.set_fragment_shader(texture_fragment_shader);
+    // the model has no material of its own, this one lets the PBR shader
+    // use its texture. Loaded materials, e.g. from glTF, take precedence.
+    let spot_material = Material {
+        name: String::from("spot"),
+        base_color_texture: Some(Texture::new(TEXTURE_PATH)),
+        metallic: 0.,
+        roughness: 0.4,
+        ..Default::default()
+    };
+    let mesh_materials = mesh_materials(&levels[0], &materials, &spot_material);
+    let mut pbr = false;
+    let mut normal_mapping = false;
+    let mut ssao: Option<Ssao> = None;
+    if Path::new(ENVIRONMENT_PATH).exists() {
+        r.set_environment(Some(Environment::new(ENVIRONMENT_PATH)));
+    }
+    r.set_cull_mode(CullMode::Back);
     let eye_pos = vec3(0.0, 0.0, 10.0);
-    let mut angle = 0f32;
 
     r.clear(BufferKind::Color | BufferKind::Depth);
 
-    r.set_model(get_model_matrix(angle));
-    r.set_view(get_view_matrix(eye_pos));
+    let view = get_view_matrix(eye_pos);
+    r.set_view(view);
     r.set_projection(get_projection_matrix(45.0, 1.0, 0.1, 50.0));
 
-    r.draw_triangle_list(&triangle_lists[0]);
+    let lights = scene_lights(view.transform_point3(Vec3::ZERO));
+    r.set_shadow_maps(render_shadow_maps(
+        &lights,
+        view,
+        get_model_matrix(angle),
+        &triangle_lists,
+    ));
+    r.set_lights(lights.clone());
+    draw_scene(
+        &mut r,
+        &ground,
+        &triangle_lists,
+        &mesh_materials,
+        get_model_matrix(angle),
+        SceneShaders {
+            meshes: texture_fragment_shader,
+            ground: phong_fragment_shader,
+        },
+    );
+    r.resolve();
     
     if dump_image {
         let angle = match args.get(2) {
@@ -130,6 +381,7 @@ fn main() {
 
 
     let mut angle_changed = true;
+    let mut display_changed = false;
 
     event_loop.run(move |event, _, control_flow| {
         autoreleasepool(|| {
@@ -160,6 +412,68 @@ fn main() {
                                 angle_changed = true;
                                 // println!("angle: {}", angle);
                             }
+                            Some(VirtualKeyCode::W) => {
+                                wireframe = match wireframe {
+                                    None => Some(WireframeMode::Triangles),
+                                    Some(WireframeMode::Triangles) => Some(WireframeMode::Polygons),
+                                    Some(WireframeMode::Polygons) => None,
+                                };
+                                angle_changed = true;
+                            }
+                            Some(VirtualKeyCode::T) => {
+                                let mut output = r.output_transform();
+                                output.tone_mapping = match output.tone_mapping {
+                                    ToneMapping::Clamp => ToneMapping::Reinhard,
+                                    ToneMapping::Reinhard => ToneMapping::Aces,
+                                    ToneMapping::Aces => ToneMapping::Filmic,
+                                    ToneMapping::Filmic => ToneMapping::Clamp,
+                                };
+                                r.set_output_transform(output);
+                                display_changed = true;
+                            }
+                            Some(VirtualKeyCode::G) => {
+                                let mut output = r.output_transform();
+                                output.srgb = !output.srgb;
+                                r.set_output_transform(output);
+                                display_changed = true;
+                            }
+                            Some(VirtualKeyCode::Minus) | Some(VirtualKeyCode::Equals) => {
+                                let mut output = r.output_transform();
+                                output.exposure += match input.virtual_keycode {
+                                    Some(VirtualKeyCode::Minus) => -0.5,
+                                    _ => 0.5,
+                                };
+                                r.set_output_transform(output);
+                                display_changed = true;
+                            }
+                            Some(VirtualKeyCode::O) => {
+                                ssao = match ssao {
+                                    Some(_) => None,
+                                    None => Some(Ssao::default()),
+                                };
+                                angle_changed = true;
+                            }
+                            Some(VirtualKeyCode::L) => {
+                                lod = (lod + 1) % levels.len();
+                                triangle_lists = mesh_triangles(&levels[lod], &materials);
+                                angle_changed = true;
+                            }
+                            Some(VirtualKeyCode::P) => {
+                                pbr = !pbr;
+                                angle_changed = true;
+                            }
+                            Some(VirtualKeyCode::N) => {
+                                normal_mapping = !normal_mapping;
+                                angle_changed = true;
+                            }
+                            Some(VirtualKeyCode::F) => {
+                                r.set_post_antialiasing(match r.post_antialiasing() {
+                                    PostAntialiasing::None => PostAntialiasing::Fxaa,
+                                    PostAntialiasing::Fxaa => PostAntialiasing::Taa,
+                                    PostAntialiasing::Taa => PostAntialiasing::None,
+                                });
+                                angle_changed = true;
+                            }
                             _ => {}
                         }
                     }
@@ -167,12 +481,69 @@ fn main() {
                 },
                 Event::MainEventsCleared => window.request_redraw(),
                 Event::RedrawRequested(_) => {
-                    if !angle_changed {
+                    // TAA keeps accumulating jittered frames, the output
+                    // transform alone only needs the resolved frame again
+                    let render = angle_changed || r.post_antialiasing() == PostAntialiasing::Taa;
+                    if !render && !display_changed {
                         return;
                     }
-                    r.clear(BufferKind::Color | BufferKind::Depth);
-                    r.set_model(get_model_matrix(angle));
-                    r.draw_triangle_list(&triangle_lists[0]);
+                    if render {
+                        r.clear(BufferKind::Color | BufferKind::Depth);
+                        if angle_changed {
+                            ground = ground_plane(
+                                lowest_point(&levels[0], get_model_matrix(angle)),
+                                4.,
+                                ground_color,
+                            );
+                            r.set_shadow_maps(render_shadow_maps(
+                                &lights,
+                                view,
+                                get_model_matrix(angle),
+                                &triangle_lists,
+                            ));
+                        }
+                        let shaders = if pbr {
+                            SceneShaders {
+                                meshes: pbr_fragment_shader,
+                                ground: pbr_fragment_shader,
+                            }
+                        } else {
+                            SceneShaders {
+                                meshes: if normal_mapping {
+                                    normal_map_fragment_shader
+                                } else {
+                                    texture_fragment_shader
+                                },
+                                ground: phong_fragment_shader,
+                            }
+                        };
+                        let model = get_model_matrix(angle);
+                        match &ssao {
+                            Some(ssao) => draw_scene_with_ssao(
+                                &mut r,
+                                &ground,
+                                &triangle_lists,
+                                &mesh_materials,
+                                model,
+                                shaders,
+                                ssao,
+                            ),
+                            None => draw_scene(
+                                &mut r,
+                                &ground,
+                                &triangle_lists,
+                                &mesh_materials,
+                                model,
+                                shaders,
+                            ),
+                        }
+                        r.resolve();
+                        if let Some(mode) = wireframe {
+                            for mesh in &levels[lod] {
+                                r.draw_wireframe(mesh, mode);
+                            }
+                        }
+                    }
                     update_texture(&r, &texture);
                     redraw(
                         &layer,
@@ -183,6 +554,7 @@ fn main() {
                         &texture,
                     );
                     angle_changed = false;
+                    display_changed = false;
                 }
 
                 _ => {}
@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+
+use glam::*;
+
+use crate::{texture::Texture, triangle::Triangle};
+
+#[derive(Clone)]
+pub struct Mesh {
+    pub name: String,
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub tex_coords: Vec<Vec2>,
+    // xyz follows the u direction of the texture, w is the handedness of
+    // the bitangent
+    pub tangents: Vec<Vec4>,
+    // faces as they were authored, may be quads or n-gons
+    pub polygons: Vec<Vec<u32>>,
+    // triangulation of `polygons`, this is what gets rasterized
+    pub indices: Vec<UVec3>,
+    // index into the materials returned alongside the meshes
+    pub material: Option<usize>,
+}
+
+// glTF style metallic-roughness material, OBJ/MTL materials are mapped onto it
+#[derive(Clone)]
+pub struct Material {
+    pub name: String,
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vec3,
+
+    pub base_color_texture: Option<Texture>,
+    pub metallic_roughness_texture: Option<Texture>,
+    pub normal_texture: Option<Texture>,
+    pub occlusion_texture: Option<Texture>,
+    pub emissive_texture: Option<Texture>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            base_color: vec4(1., 1., 1., 1.),
+            metallic: 1.,
+            roughness: 1.,
+            emissive: Vec3::ZERO,
+
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
+
+impl Mesh {
+    pub fn new(
+        name: String,
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        tex_coords: Vec<Vec2>,
+        polygons: Vec<Vec<u32>>,
+    ) -> Self {
+        let indices = polygons
+            .iter()
+            .flat_map(|polygon| triangulate_polygon(&positions, polygon))
+            .collect();
+
+        let mut mesh = Self {
+            name,
+            positions,
+            normals,
+            tex_coords,
+            tangents: vec![],
+            polygons,
+            indices,
+            material: None,
+        };
+
+        if mesh.normals.len() != mesh.positions.len() {
+            mesh.compute_normals();
+        }
+        if mesh.tex_coords.len() != mesh.positions.len() {
+            mesh.tex_coords = vec![vec2(0., 0.); mesh.positions.len()];
+        }
+        mesh.compute_tangents();
+
+        mesh
+    }
+
+    // Area weighted vertex normals from the triangulated faces
+    pub fn compute_normals(&mut self) {
+        let mut normals = vec![Vec3::ZERO; self.positions.len()];
+
+        for tri in &self.indices {
+            let [a, b, c] = tri.to_array().map(|i| self.positions[i as usize]);
+            let n = (b - a).cross(c - a);
+            for i in tri.to_array() {
+                normals[i as usize] += n;
+            }
+        }
+
+        self.normals = normals.into_iter().map(|n| n.normalize_or_zero()).collect();
+    }
+
+    // Vertex tangents from the uv gradients of the triangulated faces, summed
+    // like the normals and made orthogonal to the vertex normal
+    pub fn compute_tangents(&mut self) {
+        let mut tangents = vec![Vec3::ZERO; self.positions.len()];
+        let mut bitangents = vec![Vec3::ZERO; self.positions.len()];
+
+        for tri in &self.indices {
+            let [a, b, c] = tri.to_array().map(|i| self.positions[i as usize]);
+            let [ta, tb, tc] = tri.to_array().map(|i| self.tex_coords[i as usize]);
+            let (e1, e2) = (b - a, c - a);
+            let (duv1, duv2) = (tb - ta, tc - ta);
+
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            if det.abs() < f32::EPSILON {
+                continue;
+            }
+            // scaled by the face area like the normals, the determinant
+            // only keeps its sign
+            let r = det.signum();
+            let tangent = (e1 * duv2.y - e2 * duv1.y) * r;
+            let bitangent = (e2 * duv1.x - e1 * duv2.x) * r;
+            for i in tri.to_array() {
+                tangents[i as usize] += tangent;
+                bitangents[i as usize] += bitangent;
+            }
+        }
+
+        self.tangents = (0..self.positions.len())
+            .map(|i| {
+                let n = self.normals[i];
+                let t = tangents[i] - n * n.dot(tangents[i]);
+                let t = if t.length_squared() > 0. {
+                    t.normalize()
+                } else {
+                    n.any_orthonormal_vector()
+                };
+                let handedness = if n.cross(t).dot(bitangents[i]) < 0. {
+                    -1.
+                } else {
+                    1.
+                };
+                t.extend(handedness)
+            })
+            .collect();
+    }
+
+    pub fn triangles(&self) -> Vec<Triangle> {
+        self.triangles_with_color(vec4(0., 0., 0., 1.))
+    }
+
+    // Every vertex gets `color`, e.g. the base color of the material so its
+    // alpha makes the mesh transparent
+    pub fn triangles_with_color(&self, color: Vec4) -> Vec<Triangle> {
+        self.indices
+            .iter()
+            .map(|tri| {
+                let index_list = tri.to_array().map(|i| i as usize);
+                Triangle {
+                    v: index_list.map(|i| self.positions[i].extend(1.)),
+                    color: [color; 3],
+                    tex_coords: index_list.map(|i| self.tex_coords[i]),
+                    normal: index_list.map(|i| self.normals[i]),
+                    tangent: index_list.map(|i| self.tangents[i]),
+                }
+            })
+            .collect()
+    }
+
+    // Same mesh with the triangulation promoted to the authored faces,
+    // so exporters write out triangles instead of the original polygons
+    pub fn triangulated(&self) -> Self {
+        let mut mesh = self.clone();
+        mesh.polygons = self.indices.iter().map(|tri| tri.to_array().to_vec()).collect();
+        mesh
+    }
+
+    pub fn triangle_edges(&self) -> Vec<(u32, u32)> {
+        unique_edges(self.indices.iter().map(|tri| tri.to_array().to_vec()))
+    }
+
+    // Edges of the original faces, without the diagonals added by triangulation
+    pub fn polygon_edges(&self) -> Vec<(u32, u32)> {
+        unique_edges(self.polygons.iter().cloned())
+    }
+}
+
+fn unique_edges(faces: impl Iterator<Item = Vec<u32>>) -> Vec<(u32, u32)> {
+    let mut seen = HashSet::new();
+    let mut edges = vec![];
+
+    for face in faces {
+        for (k, &a) in face.iter().enumerate() {
+            let b = face[(k + 1) % face.len()];
+            let key = (a.min(b), a.max(b));
+            if seen.insert(key) {
+                edges.push(key);
+            }
+        }
+    }
+
+    edges
+}
+
+// Newell's method, robust for non-planar and concave polygons
+fn polygon_normal(points: &[Vec3]) -> Vec3 {
+    let mut n = Vec3::ZERO;
+    for (k, p) in points.iter().enumerate() {
+        let q = points[(k + 1) % points.len()];
+        n.x += (p.y - q.y) * (p.z + q.z);
+        n.y += (p.z - q.z) * (p.x + q.x);
+        n.z += (p.x - q.x) * (p.y + q.y);
+    }
+    n
+}
+
+#[inline]
+fn cross_2d(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    (b - a).perp_dot(c - a)
+}
+
+fn point_in_triangle_2d(p: Vec2, a: Vec2, b: Vec2, c: Vec2) -> bool {
+    cross_2d(a, b, p) >= 0. && cross_2d(b, c, p) >= 0. && cross_2d(c, a, p) >= 0.
+}
+
+// Ear-clipping triangulation of a single polygon face. The polygon is
+// projected onto the plane of its Newell normal so concave and slightly
+// non-planar faces work, output triangles keep the input winding.
+pub fn triangulate_polygon(positions: &[Vec3], polygon: &[u32]) -> Vec<UVec3> {
+    match polygon.len() {
+        0..=2 => return vec![],
+        3 => return vec![uvec3(polygon[0], polygon[1], polygon[2])],
+        _ => {}
+    }
+
+    let points: Vec<Vec3> = polygon.iter().map(|&i| positions[i as usize]).collect();
+    let normal = polygon_normal(&points);
+
+    // drop the dominant axis of the normal, and flip the remaining two
+    // so that the projected polygon is always counter-clockwise
+    let abs = normal.abs();
+    let projected: Vec<Vec2> = points
+        .iter()
+        .map(|p| {
+            if abs.x >= abs.y && abs.x >= abs.z {
+                if normal.x > 0. { vec2(p.y, p.z) } else { vec2(p.z, p.y) }
+            } else if abs.y >= abs.z {
+                if normal.y > 0. { vec2(p.z, p.x) } else { vec2(p.x, p.z) }
+            } else if normal.z > 0. {
+                vec2(p.x, p.y)
+            } else {
+                vec2(p.y, p.x)
+            }
+        })
+        .collect();
+
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    let mut triangles = Vec::with_capacity(polygon.len() - 2);
+
+    while remaining.len() > 3 {
+        let n = remaining.len();
+
+        let is_ear = |k: usize| {
+            let (ia, ib, ic) = (remaining[(k + n - 1) % n], remaining[k], remaining[(k + 1) % n]);
+            let (a, b, c) = (projected[ia], projected[ib], projected[ic]);
+
+            // reflex or degenerate corners are never ears
+            if cross_2d(a, b, c) <= 0. {
+                return false;
+            }
+
+            !remaining
+                .iter()
+                .filter(|&&it| it != ia && it != ib && it != ic)
+                .any(|&it| point_in_triangle_2d(projected[it], a, b, c))
+        };
+
+        // a valid simple polygon always has an ear, fall back to the first
+        // corner for self-intersecting or collinear input so we always finish
+        let k = (0..n).find(|&k| is_ear(k)).unwrap_or(0);
+
+        triangles.push(uvec3(
+            polygon[remaining[(k + n - 1) % n]],
+            polygon[remaining[k]],
+            polygon[remaining[(k + 1) % n]],
+        ));
+        remaining.remove(k);
+    }
+
+    triangles.push(uvec3(
+        polygon[remaining[0]],
+        polygon[remaining[1]],
+        polygon[remaining[2]],
+    ));
+
+    triangles
+}
@@ -0,0 +1,306 @@
+use std::ops::{Add, Mul};
+
+use glam::*;
+
+const FXAA_EDGE_THRESHOLD: f32 = 0.125;
+const FXAA_EDGE_THRESHOLD_MIN: f32 = 0.0312;
+const FXAA_SUBPIXEL_QUALITY: f32 = 0.75;
+const FXAA_SEARCH_STEPS: usize = 12;
+
+// Perceived brightness, the sqrt stands in for the display gamma since the
+// frame buffer is linear
+fn luma(color: Vec4) -> f32 {
+    color.xyz().dot(vec3(0.299, 0.587, 0.114)).max(0.).sqrt()
+}
+
+// Bilinear lookup with pixel centers at .5, `at` takes care of the borders
+fn bilinear<T>(at: impl Fn(isize, isize) -> T, p: Vec2) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let p = p - 0.5;
+    let (x0, y0) = (p.x.floor(), p.y.floor());
+    let (fx, fy) = (p.x - x0, p.y - y0);
+    let (x, y) = (x0 as isize, y0 as isize);
+
+    at(x, y) * ((1. - fx) * (1. - fy))
+        + at(x + 1, y) * (fx * (1. - fy))
+        + at(x, y + 1) * ((1. - fx) * fy)
+        + at(x + 1, y + 1) * (fx * fy)
+}
+
+fn clamp_to_edge(x: isize, y: isize, w: usize, h: usize) -> usize {
+    y.clamp(0, h as isize - 1) as usize * w + x.clamp(0, w as isize - 1) as usize
+}
+
+// FXAA 3.11 quality preset: finds edges from luma contrast, searches along
+// the edge for its ends and blends each pixel towards the other side of the
+// edge depending on where it sits on it
+pub fn fxaa(src: &[Vec4], w: usize, h: usize) -> Vec<Vec4> {
+    let lumas: Vec<f32> = src.iter().map(|&it| luma(it)).collect();
+    let luma_at = |x: isize, y: isize| lumas[clamp_to_edge(x, y, w, h)];
+    let color_at = |x: isize, y: isize| src[clamp_to_edge(x, y, w, h)];
+
+    let mut out = Vec::with_capacity(src.len());
+
+    for y in 0..h as isize {
+        for x in 0..w as isize {
+            let m = luma_at(x, y);
+            let (n, s) = (luma_at(x, y - 1), luma_at(x, y + 1));
+            let (west, east) = (luma_at(x - 1, y), luma_at(x + 1, y));
+
+            let max = m.max(n).max(s).max(west).max(east);
+            let min = m.min(n).min(s).min(west).min(east);
+            let range = max - min;
+
+            // not enough contrast to be an edge
+            if range < FXAA_EDGE_THRESHOLD_MIN.max(max * FXAA_EDGE_THRESHOLD) {
+                out.push(color_at(x, y));
+                continue;
+            }
+
+            let (nw, ne) = (luma_at(x - 1, y - 1), luma_at(x + 1, y - 1));
+            let (sw, se) = (luma_at(x - 1, y + 1), luma_at(x + 1, y + 1));
+
+            let horizontal = (-2. * west + nw + sw).abs()
+                + (-2. * m + n + s).abs() * 2.
+                + (-2. * east + ne + se).abs()
+                >= (-2. * n + nw + ne).abs()
+                    + (-2. * m + west + east).abs() * 2.
+                    + (-2. * s + sw + se).abs();
+
+            // the two neighbours across the edge, pick the steeper side
+            let (luma1, luma2) = if horizontal { (n, s) } else { (west, east) };
+            let (gradient1, gradient2) = ((luma1 - m).abs(), (luma2 - m).abs());
+            let steepest1 = gradient1 >= gradient2;
+            let gradient_scaled = 0.25 * gradient1.max(gradient2);
+            let luma_local_average = 0.5 * (if steepest1 { luma1 } else { luma2 } + m);
+
+            let step = if steepest1 { -1. } else { 1. };
+            let (across, along) = if horizontal {
+                (vec2(0., step), vec2(1., 0.))
+            } else {
+                (vec2(step, 0.), vec2(0., 1.))
+            };
+
+            // walk both ways along the edge until the luma stops matching it
+            let center = vec2(x as f32 + 0.5, y as f32 + 0.5);
+            let edge = center + across * 0.5;
+            let edge_luma = |p: Vec2| bilinear(luma_at, p) - luma_local_average;
+
+            let (mut p1, mut p2) = (edge - along, edge + along);
+            let (mut end1, mut end2) = (edge_luma(p1), edge_luma(p2));
+            for _ in 1..FXAA_SEARCH_STEPS {
+                let done1 = end1.abs() >= gradient_scaled;
+                let done2 = end2.abs() >= gradient_scaled;
+                if done1 && done2 {
+                    break;
+                }
+                if !done1 {
+                    p1 -= along;
+                    end1 = edge_luma(p1);
+                }
+                if !done2 {
+                    p2 += along;
+                    end2 = edge_luma(p2);
+                }
+            }
+
+            let distance1 = along.dot(center - p1);
+            let distance2 = along.dot(p2 - center);
+            let (distance, end) = if distance1 < distance2 {
+                (distance1, end1)
+            } else {
+                (distance2, end2)
+            };
+
+            // only blend when the closer end agrees with the side the
+            // center is on, otherwise the pixel is on the far part of the edge
+            let edge_offset = if (end < 0.) != (m < luma_local_average) {
+                0.5 - distance / (distance1 + distance2)
+            } else {
+                0.
+            };
+
+            // single pixel features the edge search can't see
+            let average = ((n + s + west + east) * 2. + nw + ne + sw + se) / 12.;
+            let subpixel = ((average - m).abs() / range).clamp(0., 1.);
+            let subpixel = (-2. * subpixel + 3.) * subpixel * subpixel;
+            let subpixel_offset = subpixel * subpixel * FXAA_SUBPIXEL_QUALITY;
+
+            let offset = edge_offset.max(subpixel_offset);
+            out.push(bilinear(color_at, center + across * offset));
+        }
+    }
+
+    out
+}
+
+// Radical inverse of `index` in `base`, a low discrepancy sequence in [0, 1)
+pub fn halton(mut index: usize, base: usize) -> f32 {
+    let mut result = 0.;
+    let mut f = 1.;
+    while index > 0 {
+        f /= base as f32;
+        result += f * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+// Temporal anti-aliasing: the projection is shifted by a different subpixel
+// offset every frame and the frames are accumulated into a history buffer.
+// The history is clamped to the neighbourhood of the current frame so moving
+// geometry doesn't leave trails behind.
+pub struct TemporalAa {
+    history: Vec<Vec4>,
+    frame_index: usize,
+    // weight of the current frame in the accumulation
+    pub blend: f32,
+}
+
+impl Default for TemporalAa {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TemporalAa {
+    pub fn new() -> Self {
+        Self {
+            history: vec![],
+            frame_index: 0,
+            blend: 0.1,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.frame_index = 0;
+    }
+
+    // Subpixel offset of the current frame in [-0.5, 0.5), from the first
+    // 16 points of the Halton (2, 3) sequence
+    pub fn jitter(&self) -> Vec2 {
+        let index = self.frame_index % 16 + 1;
+        vec2(halton(index, 2), halton(index, 3)) - 0.5
+    }
+
+    // Shifts `projection` by the jitter, `size` is the viewport in pixels
+    pub fn jitter_projection(&self, projection: Mat4, size: Vec2) -> Mat4 {
+        let offset = self.jitter() * 2. / size;
+        Mat4::from_translation(offset.extend(0.)) * projection
+    }
+
+    // Blends `frame` into the history, writes the result back into `frame`
+    // and moves on to the next jitter offset
+    pub fn accumulate(&mut self, frame: &mut [Vec4], w: usize, h: usize) {
+        if self.history.len() != frame.len() {
+            self.history = frame.to_vec();
+        } else {
+            let at = |x: isize, y: isize| frame[clamp_to_edge(x, y, w, h)];
+
+            for y in 0..h as isize {
+                for x in 0..w as isize {
+                    let mut min = Vec4::splat(f32::INFINITY);
+                    let mut max = Vec4::splat(f32::NEG_INFINITY);
+                    for dy in -1..=1 {
+                        for dx in -1..=1 {
+                            let color = at(x + dx, y + dy);
+                            min = min.min(color);
+                            max = max.max(color);
+                        }
+                    }
+
+                    let i = y as usize * w + x as usize;
+                    let history = self.history[i].clamp(min, max);
+                    self.history[i] = history.lerp(frame[i], self.blend);
+                }
+            }
+        }
+
+        frame.copy_from_slice(&self.history);
+        self.frame_index += 1;
+    }
+}
+
+// Curve bringing HDR values into [0, 1]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapping {
+    // cuts everything above 1
+    Clamp,
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve
+    Aces,
+    // Hable's Uncharted 2 curve
+    Filmic,
+}
+
+impl ToneMapping {
+    pub fn apply(&self, color: Vec3) -> Vec3 {
+        let mapped = match self {
+            ToneMapping::Clamp => color,
+            ToneMapping::Reinhard => color / (1. + color),
+            ToneMapping::Aces => {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                (color * (a * color + b)) / (color * (c * color + d) + e)
+            }
+            ToneMapping::Filmic => {
+                let curve = |x: Vec3| {
+                    let (a, b, c, d, e, f) = (0.15, 0.5, 0.1, 0.2, 0.02, 0.3);
+                    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+                };
+                // the curve is made for twice the exposure, 11.2 maps to white
+                let white = 11.2;
+                curve(color * 2.) / curve(Vec3::splat(white))
+            }
+        };
+        mapped.clamp(Vec3::ZERO, Vec3::ONE)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+// Turns the linear HDR frame into what gets displayed or saved: exposure,
+// then tone mapping, then the sRGB transfer function
+#[derive(Debug, Clone, Copy)]
+pub struct OutputTransform {
+    // in stops, 0 leaves the colors as they are
+    pub exposure: f32,
+    pub tone_mapping: ToneMapping,
+    pub srgb: bool,
+}
+
+impl Default for OutputTransform {
+    // Clamps and encodes the linear frame for an sRGB display
+    fn default() -> Self {
+        Self {
+            exposure: 0.,
+            tone_mapping: ToneMapping::Clamp,
+            srgb: true,
+        }
+    }
+}
+
+impl OutputTransform {
+    pub fn apply(&self, color: Vec4) -> Vec4 {
+        let exposed = color.xyz().max(Vec3::ZERO) * 2f32.powf(self.exposure);
+        let mapped = self.tone_mapping.apply(exposed);
+        let encoded = if self.srgb {
+            vec3(
+                linear_to_srgb(mapped.x),
+                linear_to_srgb(mapped.y),
+                linear_to_srgb(mapped.z),
+            )
+        } else {
+            mapped
+        };
+        encoded.extend(color.w.clamp(0., 1.))
+    }
+}
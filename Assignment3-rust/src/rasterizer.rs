@@ -1,8 +1,19 @@
+use crate::abuffer::ABuffer;
+use crate::clipping::{clip_line, clip_triangle, ClipMode, ClipVertex};
+use crate::ibl::Environment;
+use crate::mesh::{Material, Mesh};
+use crate::postprocess::{fxaa, OutputTransform, TemporalAa};
+use crate::shadow::ShadowMap;
+use crate::ssao::Ssao;
 use crate::texture::Texture;
 use crate::triangle;
 use crate::utils::image::{save_image, save_image_from_u8array};
 use crate::utils::render::TextureConvertible;
-use crate::{shader::*, triangle::Triangle, utils::render::KeyboardHandler};
+use crate::{
+    shader::*,
+    triangle::{Rect, Triangle},
+    utils::render::KeyboardHandler,
+};
 use bitflags::bitflags;
 use glam::*;
 use std::{collections::HashMap, ffi::c_void};
@@ -12,22 +23,30 @@ bitflags! {
     pub struct BufferKind: u32 {
         const Color = 0b00000001;
         const Depth = 0b00000010;
-        const All = Self::Color.bits() | Self::Depth.bits();
+        const Stencil = 0b00000100;
+        const All = Self::Color.bits() | Self::Depth.bits() | Self::Stencil.bits();
     }
 }
 
+// Vertices and sample positions are snapped to 1/256 of a pixel before
+// rasterizing, so edge functions are exact and shared edges are evaluated
+// identically
+const SUBPIXEL_BITS: u32 = 8;
+const SUBPIXEL_ONE: i64 = 1 << SUBPIXEL_BITS;
+
+// Edge function of a -> b at p, positive on the left of the edge. Evaluated
+// in i128 since vertices close to the eye can land millions of pixels away.
 #[inline]
-fn compute_barycentric_2d(x: f32, y: f32, v: &[Vec4; 3]) -> Vec3 {
-    let c1 = (x * (v[1].y - v[2].y) + (v[2].x - v[1].x) * y + v[1].x * v[2].y - v[2].x * v[1].y)
-        / (v[0].x * (v[1].y - v[2].y) + (v[2].x - v[1].x) * v[0].y + v[1].x * v[2].y
-            - v[2].x * v[1].y);
-    let c2 = (x * (v[2].y - v[0].y) + (v[0].x - v[2].x) * y + v[2].x * v[0].y - v[0].x * v[2].y)
-        / (v[1].x * (v[2].y - v[0].y) + (v[0].x - v[2].x) * v[1].y + v[2].x * v[0].y
-            - v[0].x * v[2].y);
-    let c3 = (x * (v[0].y - v[1].y) + (v[1].x - v[0].x) * y + v[0].x * v[1].y - v[1].x * v[0].y)
-        / (v[2].x * (v[0].y - v[1].y) + (v[1].x - v[0].x) * v[2].y + v[0].x * v[1].y
-            - v[1].x * v[0].y);
-    vec3(c1, c2, c3)
+fn edge_function(a: I64Vec2, b: I64Vec2, p: I64Vec2) -> i128 {
+    (b.x - a.x) as i128 * (p.y - a.y) as i128 - (b.y - a.y) as i128 * (p.x - a.x) as i128
+}
+
+// Top-left fill rule for counter-clockwise triangles with y up. Samples
+// exactly on an edge belong to the triangle only if the edge is a top edge
+// (horizontal, interior below) or a left edge (going down).
+#[inline]
+fn is_top_left(a: I64Vec2, b: I64Vec2) -> bool {
+    (a.y == b.y && b.x < a.x) || b.y < a.y
 }
 
 pub enum PrimitiveKind {
@@ -35,6 +54,238 @@ pub enum PrimitiveKind {
     Triangle,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullMode {
+    None,
+    Back,
+    Front,
+}
+
+// Winding of front-facing triangles on screen, with y pointing up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireframeMode {
+    // every edge that gets rasterized, including triangulation diagonals
+    Triangles,
+    // only the edges of the faces as authored in the source file
+    Polygons,
+}
+
+// How the samples of a pixel are placed and shaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Antialiasing {
+    // n×n ordered grid, the fragment shader runs for every covered sample
+    Ssaa(usize),
+    // 2, 4 or 8 samples on a rotated grid, coverage and depth are per sample
+    // but the fragment shader runs once per pixel and triangle
+    Msaa(usize),
+}
+
+impl Antialiasing {
+    // Sample positions inside the pixel, in [0, 1) from the bottom left corner
+    fn sample_positions(&self) -> Vec<Vec2> {
+        match *self {
+            Antialiasing::Ssaa(n) => (0..n)
+                .flat_map(|j| (0..n).map(move |i| vec2(i as f32, j as f32)))
+                .map(|it| (it * 2. + 1.) / (2 * n) as f32)
+                .collect(),
+            Antialiasing::Msaa(n) => {
+                // the standard D3D patterns, in 1/16 of a pixel from the
+                // center with y pointing down
+                let pattern: &[(i32, i32)] = match n {
+                    1 => &[(0, 0)],
+                    2 => &[(4, 4), (-4, -4)],
+                    4 => &[(-2, -6), (6, -2), (-6, 2), (2, 6)],
+                    8 => &[
+                        (1, -3),
+                        (-1, 3),
+                        (5, 1),
+                        (-3, -5),
+                        (-5, 5),
+                        (-7, -1),
+                        (3, 7),
+                        (7, -7),
+                    ],
+                    _ => panic!("MSAA supports 1, 2, 4 or 8 samples, got {n}"),
+                };
+                pattern
+                    .iter()
+                    .map(|&(x, y)| vec2(0.5 + x as f32 / 16., 0.5 - y as f32 / 16.))
+                    .collect()
+            }
+        }
+    }
+}
+
+// Depth and stencil test, compares the value of a fragment against the
+// value already in the buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Always,
+}
+
+impl CompareFunc {
+    pub fn test<T: PartialOrd>(&self, value: T, stored: T) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => value < stored,
+            CompareFunc::LessEqual => value <= stored,
+            CompareFunc::Greater => value > stored,
+            CompareFunc::GreaterEqual => value >= stored,
+            CompareFunc::Equal => value == stored,
+            CompareFunc::NotEqual => value != stored,
+            CompareFunc::Always => true,
+        }
+    }
+}
+
+// What happens to the stencil value of a sample after the tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    // clamps at 255
+    Increment,
+    IncrementWrap,
+    // clamps at 0
+    Decrement,
+    DecrementWrap,
+    Invert,
+}
+
+impl StencilOp {
+    fn apply(&self, stored: u8, reference: u8) -> u8 {
+        match self {
+            StencilOp::Keep => stored,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => reference,
+            StencilOp::Increment => stored.saturating_add(1),
+            StencilOp::IncrementWrap => stored.wrapping_add(1),
+            StencilOp::Decrement => stored.saturating_sub(1),
+            StencilOp::DecrementWrap => stored.wrapping_sub(1),
+            StencilOp::Invert => !stored,
+        }
+    }
+}
+
+// The test passes when `reference & read_mask` compares true against the
+// stored value masked the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilState {
+    pub func: CompareFunc,
+    pub reference: u8,
+    pub read_mask: u8,
+    pub write_mask: u8,
+    // stencil test failed
+    pub fail: StencilOp,
+    // stencil test passed but the depth test failed
+    pub depth_fail: StencilOp,
+    // both tests passed
+    pub pass: StencilOp,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        Self {
+            func: CompareFunc::Always,
+            reference: 0,
+            read_mask: 0xff,
+            write_mask: 0xff,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+        }
+    }
+}
+
+// How a fragment is combined with the sample already in the buffer, using
+// the alpha of the fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Replace,
+    Alpha,
+    Additive,
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn blend(&self, src: Vec4, dst: Vec4) -> Vec4 {
+        let a = src.w;
+        match self {
+            BlendMode::Replace => src,
+            BlendMode::Alpha => (src.xyz() * a + dst.xyz() * (1. - a)).extend(a + dst.w * (1. - a)),
+            BlendMode::Additive => (dst.xyz() + src.xyz() * a).extend(dst.w),
+            BlendMode::Multiply => (dst.xyz() * Vec3::ONE.lerp(src.xyz(), a)).extend(dst.w),
+        }
+    }
+}
+
+// Reconstruction filter used to resolve the samples into pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveFilter {
+    Box,
+    Tent,
+    Gaussian,
+    // B = C = 1/3, has negative lobes so it sharpens a little
+    MitchellNetravali,
+}
+
+impl ResolveFilter {
+    // Weight at `t`, the distance from the pixel center divided by the
+    // filter radius. Every filter is zero from t = 1 on.
+    fn weight(&self, t: f32) -> f32 {
+        let t = t.abs();
+        if t >= 1. {
+            return 0.;
+        }
+
+        match self {
+            ResolveFilter::Box => 1.,
+            ResolveFilter::Tent => 1. - t,
+            // sigma of half the radius
+            ResolveFilter::Gaussian => (-2. * t * t).exp(),
+            ResolveFilter::MitchellNetravali => {
+                let (b, c) = (1. / 3., 1. / 3.);
+                let x = 2. * t;
+                if x < 1. {
+                    ((12. - 9. * b - 6. * c) * x * x * x
+                        + (-18. + 12. * b + 6. * c) * x * x
+                        + (6. - 2. * b))
+                        / 6.
+                } else {
+                    ((-b - 6. * c) * x * x * x
+                        + (6. * b + 30. * c) * x * x
+                        + (-12. * b - 48. * c) * x
+                        + (8. * b + 24. * c))
+                        / 6.
+                }
+            }
+        }
+    }
+}
+
+// Screen-space anti-aliasing applied to the resolved frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostAntialiasing {
+    None,
+    Fxaa,
+    // needs a new frame every time to converge, even for a still scene
+    Taa,
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct PosBufId(usize);
 
@@ -57,18 +308,71 @@ pub struct Rasterizer {
     tex_coord: HashMap<PosBufId, Vec<Vec2>>,
 
     frame_buf: Vec<Vec4>,
+    // every pixel owns `sample_positions.len()` consecutive samples
     frame_buf_supersampled: Vec<Vec4>,
+    // samples a draw wrote the color of since it was cleared, the rest show
+    // the skybox
+    color_coverage: Vec<bool>,
     depth_buf_supersampled: Vec<f32>,
+    stencil_buf_supersampled: Vec<u8>,
+    // G-buffer targets, per sample like the color, only allocated while an
+    // MRT shader is set
+    render_targets: HashMap<RenderTarget, Vec<Vec4>>,
+    // samples an MRT draw wrote the G-buffer of since the color was cleared
+    gbuffer_coverage: Vec<bool>,
 
     model: Mat4,
     view: Mat4,
     projection: Mat4,
 
-    antialiasing: usize,
+    antialiasing: Antialiasing,
+    sample_positions: Vec<Vec2>,
+    resolve_filter: ResolveFilter,
+    // in pixels, samples of the neighbouring pixels contribute beyond 0.5
+    resolve_radius: f32,
+    post_antialiasing: PostAntialiasing,
+    taa: TemporalAa,
+    clip_mode: ClipMode,
+    // in pixels, with the origin at the bottom left like screen space
+    viewport: Rect,
+    scissor: Option<Rect>,
+    cull_mode: CullMode,
+    front_face: FrontFace,
+    culled_count: usize,
+    blend_mode: BlendMode,
+    // channels of the color buffer that get written, with none of them the
+    // fragment shader doesn't run, e.g. for depth or stencil only passes
+    color_mask: BVec4A,
+    depth_write: bool,
+    depth_func: CompareFunc,
+    // window depth of the near and far planes, swapped with reversed-Z
+    depth_range: Vec2,
+    reversed_z: bool,
+    // no stencil test when None
+    stencil: Option<StencilState>,
+    // transparent fragments of the frame when order independent
+    // transparency is on
+    abuffer: Option<ABuffer>,
 
     texture: Option<Texture>,
+    normal_map: Option<Texture>,
+    // read by material shaders like `pbr_fragment_shader`
+    material: Option<Material>,
+    // stands in for `texture` when the material brings its own textures
+    blank_texture: Texture,
     vertex_shader: VertexShaderFn,
     fragment_shader: FragmentShaderFn,
+    // takes over from `fragment_shader` when set
+    mrt_fragment_shader: Option<MrtFragmentShaderFn>,
+    material_id: u32,
+    lights: Vec<Light>,
+    shadow_maps: Vec<ShadowMap>,
+    environment: Option<Environment>,
+    // per pixel, from `compute_ambient_occlusion` until the depth is cleared
+    ambient_occlusion: Option<Vec<f32>>,
+    output_transform: OutputTransform,
+    // `frame_buf` after the output transform, what gets presented and saved
+    display_buf: Vec<Vec4>,
 }
 
 impl Rasterizer {
@@ -78,7 +382,13 @@ impl Rasterizer {
         id
     }
 
+    // `antialiasing` is the side of the SSAA grid, see `set_antialiasing`
+    // for the other modes
     pub fn new(w: usize, h: usize, antialiasing: usize) -> Self {
+        let antialiasing = Antialiasing::Ssaa(antialiasing);
+        let sample_positions = antialiasing.sample_positions();
+        let sample_count = sample_positions.len();
+
         Self {
             w,
             h,
@@ -91,19 +401,52 @@ impl Rasterizer {
             tex_coord: HashMap::new(),
 
             frame_buf: vec![vec4(0., 0., 0., 1.); w * h],
-            frame_buf_supersampled: vec![vec4(0., 0., 0., 1.); w * h * antialiasing * antialiasing],
-            // with antialiasing size?
-            depth_buf_supersampled: vec![f32::INFINITY; w * h * antialiasing * antialiasing],
+            frame_buf_supersampled: vec![vec4(0., 0., 0., 1.); w * h * sample_count],
+            color_coverage: vec![false; w * h * sample_count],
+            depth_buf_supersampled: vec![1.; w * h * sample_count],
+            stencil_buf_supersampled: vec![0; w * h * sample_count],
+            render_targets: HashMap::new(),
+            gbuffer_coverage: vec![],
 
             model: Mat4::IDENTITY,
             view: Mat4::IDENTITY,
             projection: Mat4::IDENTITY,
             antialiasing,
+            sample_positions,
+            resolve_filter: ResolveFilter::Box,
+            resolve_radius: 0.5,
+            post_antialiasing: PostAntialiasing::None,
+            taa: TemporalAa::new(),
+            clip_mode: ClipMode::NearFar,
+            viewport: Rect::new(0., 0., w as f32, h as f32),
+            scissor: None,
+            cull_mode: CullMode::None,
+            front_face: FrontFace::CounterClockwise,
+            culled_count: 0,
+            blend_mode: BlendMode::Replace,
+            color_mask: BVec4A::TRUE,
+            depth_write: true,
+            depth_func: CompareFunc::Less,
+            depth_range: vec2(0., 1.),
+            reversed_z: false,
+            stencil: None,
+            abuffer: None,
 
             texture: None,
+            normal_map: None,
+            material: None,
+            blank_texture: Texture::from_raw(1, 1, vec![1.; 3]),
 
             vertex_shader: |_| vec3(0., 0., 0.),
             fragment_shader: |_| vec4(0., 0., 0., 1.),
+            mrt_fragment_shader: None,
+            material_id: 0,
+            lights: vec![],
+            shadow_maps: vec![],
+            environment: None,
+            ambient_occlusion: None,
+            output_transform: OutputTransform::default(),
+            display_buf: vec![vec4(0., 0., 0., 1.); w * h],
         }
     }
 
@@ -113,12 +456,319 @@ impl Rasterizer {
 
     pub fn set_view(&mut self, view: Mat4) {
         self.view = view;
+        if let Some(environment) = &mut self.environment {
+            environment.set_view(view);
+        }
     }
 
     pub fn set_projection(&mut self, projection: Mat4) {
         self.projection = projection;
     }
 
+    // Reallocates the sample buffers, their contents are cleared
+    pub fn set_antialiasing(&mut self, antialiasing: Antialiasing) {
+        self.antialiasing = antialiasing;
+        self.sample_positions = antialiasing.sample_positions();
+
+        let sample_count = self.w * self.h * self.sample_positions.len();
+        self.frame_buf_supersampled = vec![vec4(0., 0., 0., 1.); sample_count];
+        self.color_coverage = vec![false; sample_count];
+        self.depth_buf_supersampled = vec![self.far_depth(); sample_count];
+        self.stencil_buf_supersampled = vec![0; sample_count];
+        for buffer in self.render_targets.values_mut() {
+            *buffer = vec![Vec4::ZERO; sample_count];
+        }
+        if !self.gbuffer_coverage.is_empty() {
+            self.gbuffer_coverage = vec![false; sample_count];
+        }
+        if self.abuffer.is_some() {
+            self.abuffer = Some(ABuffer::new(sample_count));
+        }
+    }
+
+    // The default box filter with a radius of 0.5 averages the samples of
+    // each pixel on their own
+    pub fn set_resolve_filter(&mut self, filter: ResolveFilter, radius: f32) {
+        self.resolve_filter = filter;
+        self.resolve_radius = radius;
+    }
+
+    // Weight of every sample within the filter radius, as the offset of its
+    // pixel in screen space, the sample within the pixel and the weight
+    fn resolve_taps(&self) -> Vec<(isize, isize, usize, f32)> {
+        let radius = self.resolve_radius.max(f32::EPSILON);
+        let reach = radius.ceil() as isize;
+
+        let mut taps = vec![];
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                for (s, position) in self.sample_positions.iter().enumerate() {
+                    let d = vec2(dx as f32, dy as f32) + *position - 0.5;
+                    let weight = self.resolve_filter.weight(d.x / radius)
+                        * self.resolve_filter.weight(d.y / radius);
+                    if weight != 0. {
+                        taps.push((dx, dy, s, weight));
+                    }
+                }
+            }
+        }
+        taps
+    }
+
+    // Ends the frame: filters the samples of every draw call since the last
+    // `clear` into `frame_buf`, then runs the post-process passes
+    pub fn resolve(&mut self) {
+        if let Some(abuffer) = &mut self.abuffer {
+            abuffer.composite(
+                &mut self.frame_buf_supersampled,
+                &self.depth_buf_supersampled,
+                self.depth_func,
+                self.reversed_z,
+            );
+        }
+
+        let taps = self.resolve_taps();
+        let sampling_count = self.sample_positions.len();
+        let (w, h) = (self.w as isize, self.h as isize);
+
+        for row in 0..h {
+            for x in 0..w {
+                let mut color = Vec4::ZERO;
+                let mut weight_sum = 0.;
+
+                for &(dx, dy, s, weight) in &taps {
+                    // rows go down while screen space goes up
+                    let (x, row) = (x + dx, row - dy);
+                    if x < 0 || x >= w || row < 0 || row >= h {
+                        continue;
+                    }
+                    let index = (row * w + x) as usize * sampling_count + s;
+                    color += self.frame_buf_supersampled[index] * weight;
+                    weight_sum += weight;
+                }
+
+                let pixel = (row * w + x) as usize;
+                // a radius below the distance to every sample of the pixel
+                // leaves no weight, fall back to the plain box average
+                if weight_sum <= 0. {
+                    let samples = pixel * sampling_count..(pixel + 1) * sampling_count;
+                    color = self.frame_buf_supersampled[samples].iter().sum();
+                    weight_sum = sampling_count as f32;
+                }
+
+                // negative lobes can undershoot next to bright samples
+                self.frame_buf[pixel] = (color / weight_sum).max(Vec4::ZERO);
+            }
+        }
+
+        self.post_process();
+        self.apply_output_transform();
+    }
+
+    // Exposure, tone mapping and encoding of the displayed and saved frame,
+    // `frame_buf` stays linear. The resolved frame is transformed again right
+    // away, there is no need to render it again.
+    pub fn set_output_transform(&mut self, output_transform: OutputTransform) {
+        self.output_transform = output_transform;
+        self.apply_output_transform();
+    }
+
+    pub fn output_transform(&self) -> OutputTransform {
+        self.output_transform
+    }
+
+    fn apply_output_transform(&mut self) {
+        let transform = self.output_transform;
+        for (display, &color) in self.display_buf.iter_mut().zip(&self.frame_buf) {
+            *display = transform.apply(color);
+        }
+    }
+
+    pub fn set_post_antialiasing(&mut self, post_antialiasing: PostAntialiasing) {
+        self.post_antialiasing = post_antialiasing;
+        self.taa.reset();
+    }
+
+    pub fn post_antialiasing(&self) -> PostAntialiasing {
+        self.post_antialiasing
+    }
+
+    // The projection used for rasterizing, jittered per frame for TAA
+    fn frame_projection(&self) -> Mat4 {
+        match self.post_antialiasing {
+            PostAntialiasing::Taa => {
+                let size = vec2(
+                    self.viewport.x1 - self.viewport.x0,
+                    self.viewport.y1 - self.viewport.y0,
+                );
+                self.taa.jitter_projection(self.projection, size)
+            }
+            _ => self.projection,
+        }
+    }
+
+    // Screen-space passes over the resolved frame buffer
+    fn post_process(&mut self) {
+        match self.post_antialiasing {
+            PostAntialiasing::None => {}
+            PostAntialiasing::Fxaa => self.frame_buf = fxaa(&self.frame_buf, self.w, self.h),
+            PostAntialiasing::Taa => self.taa.accumulate(&mut self.frame_buf, self.w, self.h),
+        }
+    }
+
+    pub fn set_clip_mode(&mut self, clip_mode: ClipMode) {
+        self.clip_mode = clip_mode;
+    }
+
+    // NDC [-1, 1] maps onto this rect, defaults to the whole screen
+    pub fn set_viewport(&mut self, viewport: Rect) {
+        self.viewport = viewport;
+    }
+
+    // Restricts rasterization to a rect without changing the mapping
+    pub fn set_scissor(&mut self, scissor: Option<Rect>) {
+        self.scissor = scissor;
+    }
+
+    // Inclusive pixel bounds that can be written, the intersection of the
+    // screen, the viewport and the scissor rect
+    fn raster_bounds(&self) -> Rect {
+        let mut bounds = Rect::new(0., 0., self.w as f32, self.h as f32).clamp(&self.viewport);
+        if let Some(scissor) = &self.scissor {
+            bounds = bounds.clamp(scissor);
+        }
+        Rect::new(
+            bounds.x0.floor(),
+            bounds.y0.floor(),
+            bounds.x1.ceil() - 1.,
+            bounds.y1.ceil() - 1.,
+        )
+    }
+
+    pub fn set_cull_mode(&mut self, cull_mode: CullMode) {
+        self.cull_mode = cull_mode;
+    }
+
+    pub fn set_front_face(&mut self, front_face: FrontFace) {
+        self.front_face = front_face;
+    }
+
+    // Triangles dropped by face culling since the last `reset_culled_count`
+    pub fn culled_count(&self) -> usize {
+        self.culled_count
+    }
+
+    pub fn reset_culled_count(&mut self) {
+        self.culled_count = 0;
+    }
+
+    // Decides from the winding of the clip-space vertices, which has the sign
+    // of the screen-space area even for triangles crossing the eye plane, so
+    // every input triangle is tested once before clipping. Degenerate
+    // triangles aren't culled, they cover no samples anyway.
+    fn cull(&self, clip: &[Vec4; 3]) -> bool {
+        let area = Mat3::from_cols(clip[0].xyw(), clip[1].xyw(), clip[2].xyw()).determinant();
+        if area == 0. {
+            return false;
+        }
+
+        let front_facing = match self.front_face {
+            FrontFace::CounterClockwise => area > 0.,
+            FrontFace::Clockwise => area < 0.,
+        };
+
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Back => !front_facing,
+            CullMode::Front => front_facing,
+        }
+    }
+
+    // Anything but `Replace` sorts the triangles of each draw call back to
+    // front, draw transparent meshes after the opaque ones
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
+    // Masks the channels written to the color buffer, the other channels
+    // keep their value whatever the blend mode
+    pub fn set_color_mask(&mut self, color_mask: BVec4A) {
+        self.color_mask = color_mask;
+    }
+
+    // All channels or none, depth and stencil only passes like shadow maps
+    // don't need a texture or shader
+    pub fn set_color_write(&mut self, color_write: bool) {
+        self.set_color_mask(BVec4A::splat(color_write));
+    }
+
+    // Transparent draws usually keep the depth test but don't write depth
+    pub fn set_depth_write(&mut self, depth_write: bool) {
+        self.depth_write = depth_write;
+    }
+
+    pub fn set_depth_func(&mut self, depth_func: CompareFunc) {
+        self.depth_func = depth_func;
+    }
+
+    // Window depth the near and far planes map to, [0, 1] by default
+    pub fn set_depth_range(&mut self, near: f32, far: f32) {
+        self.depth_range = vec2(near, far);
+    }
+
+    // Maps the far plane to the low end of the depth range, where floats are
+    // the most precise, and flips the depth test to `Greater`. Goes with a
+    // projection from `get_reversed_projection_matrix`, clear the depth
+    // buffer after switching.
+    pub fn set_reversed_z(&mut self, reversed_z: bool) {
+        self.reversed_z = reversed_z;
+        self.depth_func = if reversed_z {
+            CompareFunc::Greater
+        } else {
+            CompareFunc::Less
+        };
+    }
+
+    // Stencil test and ops for the following draws, None turns them off.
+    // Passes that only mark the stencil turn the color writes off.
+    pub fn set_stencil(&mut self, stencil: Option<StencilState>) {
+        self.stencil = stencil;
+    }
+
+    // Depth of the far plane, which is what the depth buffer is cleared to
+    fn far_depth(&self) -> f32 {
+        if self.reversed_z {
+            self.depth_range.x
+        } else {
+            self.depth_range.y
+        }
+    }
+
+    // Keeps every blended fragment per sample and composites them in depth
+    // order in `resolve`, so intersecting transparent triangles blend
+    // correctly without sorting
+    pub fn set_order_independent_transparency(&mut self, enabled: bool) {
+        self.abuffer = if enabled {
+            Some(ABuffer::new(self.frame_buf_supersampled.len()))
+        } else {
+            None
+        };
+    }
+
+    // Sorts by the average screen-space depth, farthest first. Not needed
+    // when the A-buffer sorts the fragments.
+    fn sort_back_to_front<T>(&self, triangles: &mut [T], triangle: impl Fn(&T) -> &Triangle) {
+        if self.blend_mode == BlendMode::Replace || self.abuffer.is_some() {
+            return;
+        }
+        let depth = |t: &Triangle| t.v[0].z + t.v[1].z + t.v[2].z;
+        if self.reversed_z {
+            triangles.sort_by(|a, b| depth(triangle(a)).total_cmp(&depth(triangle(b))));
+        } else {
+            triangles.sort_by(|a, b| depth(triangle(b)).total_cmp(&depth(triangle(a))));
+        }
+    }
+
     pub fn load_positions(&mut self, pos_buf: Vec<Vec3>) -> PosBufId {
         let id = PosBufId(self.get_next_id());
         self.pos_buf.insert(id, pos_buf);
@@ -131,6 +781,8 @@ impl Rasterizer {
         id
     }
 
+    // RGB in 0-255 like `Triangle::set_color_rgb`, alpha in [0, 1] so that
+    // buffers storing w = 1 stay opaque
     pub fn load_colors(&mut self, col_buf: Vec<Vec4>) -> ColBufId {
         let id = ColBufId(self.get_next_id());
         self.col_buf.insert(id, col_buf);
@@ -152,9 +804,22 @@ impl Rasterizer {
     pub fn clear(&mut self, kind: BufferKind) {
         if kind.contains(BufferKind::Color) {
             self.frame_buf_supersampled.fill(vec4(0., 0., 0., 1.));
+            self.color_coverage.fill(false);
+            for buffer in self.render_targets.values_mut() {
+                buffer.fill(Vec4::ZERO);
+            }
+            self.gbuffer_coverage.fill(false);
+            if let Some(abuffer) = &mut self.abuffer {
+                abuffer.clear();
+            }
         }
         if kind.contains(BufferKind::Depth) {
-            self.depth_buf_supersampled.fill(f32::INFINITY);
+            let far = self.far_depth();
+            self.depth_buf_supersampled.fill(far);
+            self.ambient_occlusion = None;
+        }
+        if kind.contains(BufferKind::Stencil) {
+            self.stencil_buf_supersampled.fill(0);
         }
     }
 
@@ -167,48 +832,54 @@ impl Rasterizer {
     ) {
         match primitive_kind {
             PrimitiveKind::Triangle => {
-                let triangles: Vec<Triangle> = {
+                let mut culled = 0;
+                let mut triangles: Vec<Triangle> = {
                     let buf = &self.pos_buf[pos_buf];
                     let ind = &self.ind_buf[ind_buf];
                     let col = &self.col_buf[col_buf];
 
-                    let f1 = 99.9f32 / 2.0;
-                    let f2 = 100.1f32 / 2.0;
-
-                    let mvp = self.projection * self.view * self.model;
+                    let mvp = self.frame_projection() * self.view * self.model;
 
                     ind.into_iter()
                         .map(|vi: &UVec3| {
+                            (vi, vi.to_array().map(|i| mvp * buf[i as usize].extend(1.0)))
+                        })
+                        .filter(|(_, clip)| {
+                            let is_culled = self.cull(clip);
+                            culled += is_culled as usize;
+                            !is_culled
+                        })
+                        .flat_map(|(vi, clip)| {
+                            let vertices = [0, 1, 2].map(|n| ClipVertex {
+                                position: clip[n],
+                                view_pos: Vec3::ZERO,
+                                color: {
+                                    let color = col[vi[n] as usize];
+                                    (color.xyz() / 255.).extend(color.w)
+                                },
+                                tex_coord: Vec2::ZERO,
+                                normal: Vec3::ZERO,
+                                tangent: Vec4::ZERO,
+                            });
+
+                            clip_triangle(&vertices, self.clip_mode, self.reversed_z)
+                        })
+                        .map(|vertices| {
                             let mut t = Triangle::zeros();
 
-                            vec![vi.x, vi.y, vi.z]
-                                .iter()
-                                .map(|&i| {
-                                    let mut vec = mvp * (buf[i as usize].extend(1.0));
-                                    vec /= vec.w;
-                                    vec.x = 0.5 * (self.w as f32) * (vec.x + 1.0);
-                                    vec.y = 0.5 * (self.h as f32) * (vec.y + 1.0);
-                                    vec.z = vec.z * f1 + f2;
-                                    vec
-                                })
-                                .enumerate()
-                                .for_each(|it| {
-                                    t.set_vertex(it.0, it.1);
-                                });
-
-                            vec![vi.x, vi.y, vi.z]
-                                .iter()
-                                .enumerate()
-                                .for_each(|(n, &it)| {
-                                    let color = col[it as usize];
-                                    t.set_color_rgb(n, color.x, color.y, color.z);
-                                });
+                            vertices.iter().enumerate().for_each(|(n, it)| {
+                                t.set_vertex(n, self.viewport_transform(it.position));
+                                t.set_color_vec(n, it.color);
+                            });
                             t
                         })
                         .collect()
                 };
+                self.culled_count += culled;
+
+                self.sort_back_to_front(&mut triangles, |it| it);
 
-                for (i, t) in triangles.iter().enumerate() {
+                for t in triangles.iter() {
                     self.rasterize_triangle_antialiased(&t);
                 }
 
@@ -232,82 +903,104 @@ impl Rasterizer {
                 //     "image2.png",
                 // );
                 // exit(0);
-
-                let sampling_count = (self.antialiasing * self.antialiasing) as f32;
-
-                for i in 0..self.w {
-                    for j in 0..self.h {
-                        let mut color = vec4(0., 0., 0., 1.);
-                        for k in 0..self.antialiasing {
-                            for l in 0..self.antialiasing {
-                                let idx = (i * self.antialiasing + k)
-                                    + (j * self.antialiasing + l) * self.w * self.antialiasing;
-                                color += self.frame_buf_supersampled[idx];
-                            }
-                        }
-                        color /= sampling_count;
-                        self.frame_buf[i + j * self.w] = color;
-                    }
-                }
             }
             PrimitiveKind::Line => {}
         }
     }
 
     pub fn draw_triangle_list(&mut self, triangles: &Vec<Triangle>) {
-        let f1 = 99.9f32 / 2.;
-        let f2 = 100.1f32 / 2.;
         let vm = self.view * self.model;
-        let mvp = self.projection * self.view * self.model;
+        let mvp = self.frame_projection() * self.view * self.model;
+        let normal_matrix = Mat3::from_mat4(vm).inverse().transpose();
 
-        let triangles: Vec<(Triangle, _)> = triangles
-            .iter()
-            .map(|t| {
+        // tangents follow the surface, unlike the normals
+        let tangent_matrix = Mat3::from_mat4(vm);
 
+        let mut culled = 0;
+        let mut triangles: Vec<(Triangle, _)> = triangles
+            .iter()
+            .map(|t| (t, t.v.map(|it| mvp * it)))
+            .filter(|(_, clip)| {
+                let is_culled = self.cull(clip);
+                culled += is_culled as usize;
+                !is_culled
+            })
+            .flat_map(|(t, clip)| {
                 let viewspace_pos = t.v.map(|it| (vm * it).xyz());
 
-                let mut t: Triangle = t.clone();
-
-                let transformed_vertex =
-                    t.v.iter()
-                        .map(|&v| {
-                            let mut vec = mvp * v;
-                            vec /= vec.w;
-                            vec.x = 0.5 * (self.w as f32) * (vec.x + 1.0);
-                            vec.y = 0.5 * (self.h as f32) * (vec.y + 1.0);
-                            vec.z = vec.z * f1 + f2;
-                            vec
-                        })
-                        .enumerate()
-                        .collect::<Vec<_>>();
-
-                transformed_vertex.iter().for_each(|it| {
-                    t.set_vertex(it.0, it.1);
+                let vertices = [0, 1, 2].map(|n| ClipVertex {
+                    position: clip[n],
+                    view_pos: viewspace_pos[n],
+                    color: t.color[n],
+                    tex_coord: t.tex_coords[n],
+                    normal: normal_matrix * t.normal[n],
+                    tangent: (tangent_matrix * t.tangent[n].xyz()).extend(t.tangent[n].w),
                 });
-                
-                (t, viewspace_pos)
+
+                clip_triangle(&vertices, self.clip_mode, self.reversed_z)
+            })
+            .map(|vertices| {
+                let t = Triangle {
+                    v: vertices.map(|it| self.viewport_transform(it.position)),
+                    color: vertices.map(|it| it.color),
+                    tex_coords: vertices.map(|it| it.tex_coord),
+                    normal: vertices.map(|it| it.normal),
+                    tangent: vertices.map(|it| it.tangent),
+                };
+
+                (t, vertices.map(|it| it.view_pos))
             })
             .collect::<_>();
+        self.culled_count += culled;
+
+        self.sort_back_to_front(&mut triangles, |it| &it.0);
 
         for (t, viewspace_pos) in triangles {
             self.rasterize_triangle_antialiased_with_shader(&t, &viewspace_pos);
         }
+    }
 
-        let sampling_count = (self.antialiasing * self.antialiasing) as f32;
+    // Perspective divide and viewport mapping from clip space to screen space
+    fn viewport_transform(&self, clip: Vec4) -> Vec4 {
+        let vp = &self.viewport;
+        let mut vec = clip / clip.w;
+        vec.x = vp.x0 + 0.5 * (vp.x1 - vp.x0) * (vec.x + 1.0);
+        vec.y = vp.y0 + 0.5 * (vp.y1 - vp.y0) * (vec.y + 1.0);
+
+        // NDC depth is in [-1, 1], or [0, 1] from the far plane to the near
+        // one with reversed-Z, which is kept as is so far depths stay close
+        // to 0
+        let t = if self.reversed_z {
+            vec.z
+        } else {
+            0.5 * (vec.z + 1.)
+        };
+        let (near, far) = (self.depth_range.x, self.depth_range.y);
+        vec.z = near + (far - near) * t;
+        vec
+    }
 
-        for i in 0..self.w {
-            for j in 0..self.h {
-                let mut color = vec4(0., 0., 0., 1.);
-                for k in 0..self.antialiasing {
-                    for l in 0..self.antialiasing {
-                        let idx = (i * self.antialiasing + k)
-                            + (j * self.antialiasing + l) * self.w * self.antialiasing;
-                        color += self.frame_buf_supersampled[idx];
-                    }
-                }
-                color /= sampling_count;
-                self.frame_buf[i + j * self.w] = color;
-            }
+    // Draws the edges of `mesh` straight into the resolved frame buffer,
+    // call it after `resolve`
+    pub fn draw_wireframe(&mut self, mesh: &Mesh, mode: WireframeMode) {
+        let mvp = self.projection * self.view * self.model;
+
+        let clip_pos: Vec<Vec4> = mesh.positions.iter().map(|p| mvp * p.extend(1.)).collect();
+
+        let edges = match mode {
+            WireframeMode::Triangles => mesh.triangle_edges(),
+            WireframeMode::Polygons => mesh.polygon_edges(),
+        };
+
+        for (a, b) in edges {
+            let (a, b) = (clip_pos[a as usize], clip_pos[b as usize]);
+            // x and y are always clipped, `draw_line` can't take off-screen points
+            let Some((a, b)) = clip_line(a, b, ClipMode::Frustum, self.reversed_z) else {
+                continue;
+            };
+            let a = self.viewport_transform(a);
+            let b = self.viewport_transform(b);
+            self.draw_line(a.xyz(), b.xyz());
         }
     }
 
@@ -361,8 +1054,9 @@ impl Rasterizer {
             return;
         };
 
-        let i = (self.h - point.1) * self.w + point.0;
+        let i = (self.h - 1 - point.1) * self.w + point.0;
         self.frame_buf[i] = color;
+        self.display_buf[i] = self.output_transform.apply(color);
     }
 
     #[inline]
@@ -371,8 +1065,9 @@ impl Rasterizer {
             return;
         };
 
-        let i = (self.h - point.1) * self.w + point.0;
-        self.depth_buf_supersampled[i] = depth;
+        let sampling_count = self.sample_positions.len();
+        let i = self.get_index(point.0, point.1) * sampling_count;
+        self.depth_buf_supersampled[i..i + sampling_count].fill(depth);
     }
 
     // #[inline]
@@ -386,117 +1081,343 @@ impl Rasterizer {
     // }
 
     pub fn get_index(&self, x: usize, y: usize) -> usize {
-        return (self.h - y) * self.w + x;
-    }
-
-    pub fn get_index_for_antialiased(&self, x: usize, y: usize) -> usize {
-        return (self.h * self.antialiasing - y) * self.w * self.antialiasing + x;
-    }
-
-    fn get_sample_points(&self, x: f32, y: f32) -> Vec<(f32, f32, usize, usize)> {
-        let half_subpixel_size = 0.5 / self.antialiasing as f32;
-        let mut points = vec![];
-        for i in 0..self.antialiasing {
-            for j in 0..self.antialiasing {
-                let x = x + (i as f32 * 2. + 1.) * half_subpixel_size;
-                let y = y + (j as f32 * 2. + 1.) * half_subpixel_size;
-                points.push((
-                    x,
-                    y,
-                    i + self.antialiasing * x as usize,
-                    j + self.antialiasing * y as usize,
-                ));
+        return (self.h - 1 - y) * self.w + x;
+    }
+
+    // Every sample covered by `t` as an index into the sample buffers, with
+    // the barycentric coordinates of the sample in the vertex order of `t`.
+    // Samples of the same pixel are consecutive.
+    fn covered_samples(&self, t: &Triangle) -> Vec<(usize, Vec3)> {
+        let bbox = t.bounding_box().clamp(&self.raster_bounds());
+        if bbox.is_empty() {
+            return vec![];
+        }
+
+        let snap = |it: Vec2| {
+            i64vec2(
+                (it.x * SUBPIXEL_ONE as f32).round() as i64,
+                (it.y * SUBPIXEL_ONE as f32).round() as i64,
+            )
+        };
+        let v = t.v.map(|it| snap(it.xy()));
+
+        let area = edge_function(v[0], v[1], v[2]);
+        if area == 0 {
+            return vec![];
+        }
+
+        // make the triangle counter-clockwise, `order` maps back to `t`
+        let order = if area > 0 { [0, 1, 2] } else { [0, 2, 1] };
+        let p = order.map(|i| v[i]);
+        let area = area.abs();
+
+        // edge k is opposite to vertex k, so its function is the k-th weight
+        let edges = [(p[1], p[2]), (p[2], p[0]), (p[0], p[1])];
+        let bias = edges.map(|(a, b)| if is_top_left(a, b) { 0 } else { -1 });
+        let gradient = |(a, b): (I64Vec2, I64Vec2), d: I64Vec2| {
+            -(b.y as i128 - a.y as i128) * d.x as i128 + (b.x as i128 - a.x as i128) * d.y as i128
+        };
+        let step_x = edges.map(|edge| gradient(edge, i64vec2(SUBPIXEL_ONE, 0)));
+        let step_y = edges.map(|edge| gradient(edge, i64vec2(0, SUBPIXEL_ONE)));
+
+        // edge functions at each sample, relative to the pixel corner
+        let sample_offsets: Vec<[i128; 3]> = self
+            .sample_positions
+            .iter()
+            .map(|&it| edges.map(|edge| gradient(edge, snap(it))))
+            .collect();
+        let sampling_count = sample_offsets.len();
+
+        // set up and stepped in i128 like `edge_function`, far off screen
+        // vertices make edges whose functions don't fit an i64 even inside
+        // the clamped bounding box
+        let origin = i64vec2(
+            bbox.x_start() as i64 * SUBPIXEL_ONE,
+            bbox.y_start() as i64 * SUBPIXEL_ONE,
+        );
+        let mut row = edges.map(|(a, b)| edge_function(a, b, origin));
+
+        let inv_area = 1. / area as f32;
+        let mut samples = vec![];
+
+        for y in bbox.y_range() {
+            let mut e = row;
+            for x in bbox.x_range() {
+                let pixel = self.get_index(x, y);
+                for (s, offset) in sample_offsets.iter().enumerate() {
+                    let e = [0, 1, 2].map(|k| e[k] + offset[k]);
+                    if (0..3).all(|k| e[k] + bias[k] >= 0) {
+                        let mut c = Vec3::ZERO;
+                        for k in 0..3 {
+                            c[order[k]] = e[k] as f32 * inv_area;
+                        }
+                        samples.push((pixel * sampling_count + s, c));
+                    }
+                }
+                for k in 0..3 {
+                    e[k] += step_x[k];
+                }
+            }
+            for k in 0..3 {
+                row[k] += step_y[k];
             }
         }
-        points
+
+        samples
     }
 
-    fn rasterize_triangle_antialiased(&mut self, t: &Triangle) {
-        let bbox = t.bounding_box();
-        // let antialiasing = self.antialiasing as f32;
+    // Stencil and depth test of one sample. Updates the stencil value with the
+    // outcome and tells whether the fragment gets written.
+    fn test_sample(&mut self, index: usize, depth: f32) -> bool {
+        let depth_passed = self
+            .depth_func
+            .test(depth, self.depth_buf_supersampled[index]);
+        self.test_stencil(index, depth_passed)
+    }
 
-        let points: Vec<(f32, f32, usize, usize)> = bbox
-            .x_range()
-            .flat_map(|x| bbox.y_range().map(move |y| (x as f32, y as f32)))
-            .flat_map(|(i, j)| self.get_sample_points(i as _, j as _))
-            .collect();
+    // Stencil test of one sample given the outcome of its depth test
+    fn test_stencil(&mut self, index: usize, depth_passed: bool) -> bool {
+        let Some(stencil) = self.stencil else {
+            return depth_passed;
+        };
 
-        for (x, y, i, j) in points {
-            let c = compute_barycentric_2d(x, y, &t.v);
-            let inside = c.x >= 0. && c.y >= 0. && c.z >= 0.;
+        let stored = self.stencil_buf_supersampled[index];
+        let stencil_passed = stencil.func.test(
+            stencil.reference & stencil.read_mask,
+            stored & stencil.read_mask,
+        );
 
-            if !inside {
-                continue;
-            }
+        let (passed, op) = match (stencil_passed, depth_passed) {
+            (false, _) => (false, stencil.fail),
+            (true, false) => (false, stencil.depth_fail),
+            (true, true) => (true, stencil.pass),
+        };
 
-            if i >= self.w * self.antialiasing || j >= self.h * self.antialiasing {
-                continue;
+        let value = op.apply(stored, stencil.reference);
+        self.stencil_buf_supersampled[index] =
+            (stored & !stencil.write_mask) | (value & stencil.write_mask);
+        passed
+    }
+
+    // Blends `color` into a sample that passed the tests. With OIT on,
+    // blended fragments go to the A-buffer and are composited in `resolve`.
+    // They don't write depth, which would hide the ones behind them.
+    fn write_sample(&mut self, index: usize, color: Vec4, depth: f32) {
+        match &mut self.abuffer {
+            _ if !self.color_mask.any() => {}
+            Some(abuffer) if self.blend_mode != BlendMode::Replace => {
+                abuffer.push(index, depth, color, self.blend_mode, self.color_mask);
+                return;
             }
+            _ => {
+                let dst = self.frame_buf_supersampled[index];
+                let blended = self.blend_mode.blend(color, dst);
+                self.frame_buf_supersampled[index] = Vec4::select(self.color_mask, blended, dst);
+                self.color_coverage[index] = true;
+            }
+        }
+        if self.depth_write {
+            self.depth_buf_supersampled[index] = depth;
+        }
+    }
 
-            let supersampled_index = self.get_index_for_antialiased(i, j);
+    fn rasterize_triangle_antialiased(&mut self, t: &Triangle) {
+        for (supersampled_index, c) in self.covered_samples(t) {
             let depth = t.v[0].z * c.x + t.v[1].z * c.y + t.v[2].z * c.z;
-            if depth < self.depth_buf_supersampled[supersampled_index] {
-                let colors = Mat3::from_cols(t.color[0].xyz(), t.color[1].xyz(), t.color[2].xyz());
-                let color = (colors * c).extend(1.);
-                self.frame_buf_supersampled[supersampled_index] = color;
-                self.depth_buf_supersampled[supersampled_index] = depth;
+            if self.test_sample(supersampled_index, depth) {
+                let color = t.color[0] * c.x + t.color[1] * c.y + t.color[2] * c.z;
+                self.write_sample(supersampled_index, color, depth);
             }
         }
     }
 
-    fn rasterize_triangle_antialiased_with_shader(&mut self, t: &Triangle, view_pos: &[Vec3; 3]) {
-        let bbox = t.bounding_box();
-        // let antialiasing = self.antialiasing as f32;
+    // Runs the fragment shader at barycentric coordinates `c` of `t` for the
+    // sample at `index`, the alpha of the vertex colors scales the alpha of
+    // the shader
+    fn shade(&self, t: &Triangle, view_pos: &[Vec3; 3], c: Vec3, index: usize) -> FragmentOutput {
+        let texture = match (&self.texture, &self.material) {
+            (Some(texture), _) => texture,
+            (None, Some(_)) => &self.blank_texture,
+            (None, None) => panic!("Referencing empty texture"),
+        };
 
-        let points: Vec<(f32, f32, usize, usize)> = bbox
-            .x_range()
-            .flat_map(|x| bbox.y_range().map(move |y| (x as f32, y as f32)))
-            .flat_map(|(i, j)| self.get_sample_points(i as _, j as _))
-            .collect();
+        let color = t.color[0] * c.x + t.color[1] * c.y + t.color[2] * c.z;
+        let payload = FragmentShaderPayload {
+            view_pos: view_pos[0] * c.x + view_pos[1] * c.y + view_pos[2] * c.z,
+            color: color.xyz(),
+            normal: t.normal[0] * c.x + t.normal[1] * c.y + t.normal[2] * c.z,
+            tangent: t.tangent[0] * c.x + t.tangent[1] * c.y + t.tangent[2] * c.z,
+            tex_coords: t.tex_coords[0] * c.x + t.tex_coords[1] * c.y + t.tex_coords[2] * c.z,
+            texture,
+            normal_map: self.normal_map.as_ref(),
+            material: self.material.as_ref(),
+            material_id: self.material_id,
+            lights: &self.lights,
+            shadow_maps: &self.shadow_maps,
+            environment: self.environment.as_ref(),
+            ambient_occlusion: self
+                .ambient_occlusion
+                .as_ref()
+                .map_or(1., |it| it[index / self.sample_positions.len()]),
+        };
 
-        match &self.texture {
-            Some(texture) => {
-                for (x, y, i, j) in points {
-                    let c = compute_barycentric_2d(x, y, &t.v);
-                    let inside = c.x >= 0. && c.y >= 0. && c.z >= 0.;
+        let mut output = match self.mrt_fragment_shader {
+            Some(shader) => shader(payload),
+            None => {
+                let mut output = FragmentOutput::default();
+                output[RenderTarget::Color] = (self.fragment_shader)(payload);
+                output
+            }
+        };
+        output[RenderTarget::Color].w *= color.w;
+        output
+    }
 
-                    if !inside {
-                        continue;
-                    }
+    // The G-buffer targets are stored as written, without blending. Blended
+    // draws don't make it into the G-buffer, a surface behind them would
+    // lose its lighting.
+    fn write_render_targets(&mut self, index: usize, output: &FragmentOutput) {
+        if self.render_targets.is_empty() || self.blend_mode != BlendMode::Replace {
+            return;
+        }
+        for (target, buffer) in self.render_targets.iter_mut() {
+            buffer[index] = output[*target];
+        }
+        self.gbuffer_coverage[index] = true;
+    }
 
-                    if i >= self.w * self.antialiasing || j >= self.h * self.antialiasing {
-                        continue;
-                    }
+    // Lights the samples the MRT shader filled the G-buffer of with the
+    // lights, shadow maps, environment and ambient occlusion of the scene,
+    // replacing their color. `material_id` indexes `materials`. Call it
+    // after the opaque draws, then draw the blended ones with a forward
+    // shader before `resolve`.
+    pub fn shade_deferred(&mut self, shader: DeferredShaderFn, materials: &[Material]) {
+        if self.render_targets.is_empty() {
+            panic!("Deferred shading needs an MRT fragment shader");
+        }
+
+        let sampling_count = self.sample_positions.len();
+        let target = |target: RenderTarget, index: usize| self.render_targets[&target][index];
+
+        for index in 0..self.frame_buf_supersampled.len() {
+            if !self.gbuffer_coverage[index] {
+                continue;
+            }
+
+            let sample = GBufferSample {
+                albedo: target(RenderTarget::Albedo, index).xyz(),
+                normal: target(RenderTarget::Normal, index).xyz(),
+                view_pos: target(RenderTarget::ViewPosition, index).xyz(),
+                material_id: target(RenderTarget::MaterialId, index).x as u32,
+            };
+            let lighting = Lighting {
+                lights: &self.lights,
+                shadow_maps: &self.shadow_maps,
+                environment: self.environment.as_ref(),
+                ambient_occlusion: self
+                    .ambient_occlusion
+                    .as_ref()
+                    .map_or(1., |it| it[index / sampling_count]),
+            };
+            let material = materials.get(sample.material_id as usize);
+            self.frame_buf_supersampled[index] = shader(&sample, material, &lighting);
+            self.color_coverage[index] = true;
+        }
+    }
+
+    fn rasterize_triangle_antialiased_with_shader(&mut self, t: &Triangle, view_pos: &[Vec3; 3]) {
+        let samples = self.covered_samples(t);
+        let sampling_count = self.sample_positions.len();
+
+        if !self.color_mask.any() {
+            for (supersampled_index, c) in samples {
+                let depth = t.v[0].z * c.x + t.v[1].z * c.y + t.v[2].z * c.z;
+                if self.test_sample(supersampled_index, depth) {
+                    self.write_sample(supersampled_index, Vec4::ZERO, depth);
+                }
+            }
+            return;
+        }
 
-                    let supersampled_index = self.get_index_for_antialiased(i, j);
+        match self.antialiasing {
+            Antialiasing::Ssaa(_) => {
+                for (supersampled_index, c) in samples {
                     let depth = t.v[0].z * c.x + t.v[1].z * c.y + t.v[2].z * c.z;
-                    if depth < self.depth_buf_supersampled[supersampled_index] {
-                        let texcoord = vec2(
-                            t.tex_coords[0].x * c[0]
-                                + t.tex_coords[1].x * c[1]
-                                + t.tex_coords[2].x * c[2],
-                            t.tex_coords[0].y * c[0]
-                                + t.tex_coords[1].y * c[1]
-                                + t.tex_coords[2].y * c[2],
-                        );
-
-                        let color = texture.get_color_by_tex_coord(texcoord);
-                        self.frame_buf_supersampled[supersampled_index] = color.extend(1.);
-
-                        self.depth_buf_supersampled[supersampled_index] = depth;
+                    if self.test_sample(supersampled_index, depth) {
+                        let output = self.shade(t, view_pos, c, supersampled_index);
+                        self.write_sample(supersampled_index, output[RenderTarget::Color], depth);
+                        self.write_render_targets(supersampled_index, &output);
                     }
                 }
             }
-            None => {
-                panic!("Referencing empty texture");
+            Antialiasing::Msaa(_) => {
+                for fragment in
+                    samples.chunk_by(|a, b| a.0 / sampling_count == b.0 / sampling_count)
+                {
+                    let mut visible: Vec<(usize, f32)> = vec![];
+                    for &(index, c) in fragment {
+                        let depth = t.v[0].z * c.x + t.v[1].z * c.y + t.v[2].z * c.z;
+                        if self.test_sample(index, depth) {
+                            visible.push((index, depth));
+                        }
+                    }
+                    if visible.is_empty() {
+                        continue;
+                    }
+
+                    // shade at the centroid of the covered samples, unlike the
+                    // pixel center it never extrapolates outside the triangle
+                    let centroid =
+                        fragment.iter().map(|it| it.1).sum::<Vec3>() / fragment.len() as f32;
+                    let output = self.shade(t, view_pos, centroid, fragment[0].0);
+
+                    for (supersampled_index, depth) in visible {
+                        self.write_sample(supersampled_index, output[RenderTarget::Color], depth);
+                        self.write_render_targets(supersampled_index, &output);
+                    }
+                }
             }
         }
     }
 
+    // Copies the resolved frame into a texture, so an offscreen rasterizer
+    // can feed a later draw of another one
+    pub fn to_texture(&self) -> Texture {
+        let data = self
+            .frame_buf
+            .iter()
+            .flat_map(|it| it.xyz().to_array())
+            .collect();
+        Texture::from_raw(self.w as u32, self.h as u32, data)
+    }
+
+    // Closest depth of every pixel over its samples, rows from top to bottom
+    pub fn resolve_depth(&self) -> Vec<f32> {
+        let sampling_count = self.sample_positions.len();
+        self.depth_buf_supersampled
+            .chunks(sampling_count)
+            .map(|samples| {
+                if self.reversed_z {
+                    samples.iter().copied().fold(f32::NEG_INFINITY, f32::max)
+                } else {
+                    samples.iter().copied().fold(f32::INFINITY, f32::min)
+                }
+            })
+            .collect()
+    }
+
     pub fn set_texture(&mut self, texture: Texture) {
         self.texture = Some(texture);
     }
 
+    pub fn set_normal_map(&mut self, normal_map: Texture) {
+        self.normal_map = Some(normal_map);
+    }
+
+    pub fn set_material(&mut self, material: Material) {
+        self.material = Some(material);
+    }
+
     pub fn set_vertex_shader(&mut self, vertex_shader: VertexShaderFn) {
         self.vertex_shader = vertex_shader;
     }
@@ -505,6 +1426,129 @@ impl Rasterizer {
         self.fragment_shader = fragment_shader;
     }
 
+    // A shader writing several render targets at once, e.g. a G-buffer for
+    // `shade_deferred`. None goes back to `fragment_shader`.
+    pub fn set_mrt_fragment_shader(&mut self, shader: Option<MrtFragmentShaderFn>) {
+        self.mrt_fragment_shader = shader;
+
+        if shader.is_none() {
+            self.render_targets.clear();
+            self.gbuffer_coverage.clear();
+        } else if self.render_targets.is_empty() {
+            let sample_count = self.frame_buf_supersampled.len();
+            self.gbuffer_coverage = vec![false; sample_count];
+            for target in RenderTarget::ALL {
+                if target != RenderTarget::Color {
+                    self.render_targets
+                        .insert(target, vec![Vec4::ZERO; sample_count]);
+                }
+            }
+        }
+    }
+
+    pub fn set_material_id(&mut self, material_id: u32) {
+        self.material_id = material_id;
+    }
+
+    // The lights of the scene, passed to the fragment shaders
+    pub fn set_lights(&mut self, lights: Vec<Light>) {
+        self.lights = lights;
+    }
+
+    // Shadow maps the fragment shaders look up, see `ShadowMap`
+    pub fn set_shadow_maps(&mut self, shadow_maps: Vec<ShadowMap>) {
+        self.shadow_maps = shadow_maps;
+    }
+
+    // Screen-space ambient occlusion from the depth drawn so far, which the
+    // fragment shaders get to scale their ambient light with. Draw the
+    // opaque geometry with color writes off first, then draw it again with
+    // `CompareFunc::LessEqual`. Assumes the viewport covers the frame.
+    pub fn compute_ambient_occlusion(&mut self, ssao: &Ssao) {
+        let far = self.far_depth();
+        let inverse_projection = self.frame_projection().inverse();
+        let (near_depth, far_depth) = (self.depth_range.x, self.depth_range.y);
+
+        let positions: Vec<Option<Vec3>> = self
+            .resolve_depth()
+            .into_iter()
+            .enumerate()
+            .map(|(pixel, depth)| {
+                if depth == far {
+                    return None;
+                }
+
+                // undo `viewport_transform` at the pixel center
+                let x = pixel % self.w;
+                let y = self.h - 1 - pixel / self.w;
+                let t = (depth - near_depth) / (far_depth - near_depth);
+                let z = if self.reversed_z { t } else { 2. * t - 1. };
+                let ndc = vec3(
+                    (x as f32 + 0.5) / self.w as f32 * 2. - 1.,
+                    (y as f32 + 0.5) / self.h as f32 * 2. - 1.,
+                    z,
+                );
+
+                let p = inverse_projection * ndc.extend(1.);
+                Some(p.xyz() / p.w)
+            })
+            .collect();
+
+        self.ambient_occlusion =
+            Some(ssao.compute(&positions, self.w, self.h, self.frame_projection()));
+    }
+
+    // Image based ambient light for the fragment shaders and `draw_skybox`,
+    // it turns with the view so the environment stays put in world space
+    pub fn set_environment(&mut self, environment: Option<Environment>) {
+        self.environment = environment;
+        if let Some(environment) = &mut self.environment {
+            environment.set_view(self.view);
+        }
+    }
+
+    // Fills the samples no draw wrote the color of with the environment seen
+    // through them, within the viewport and scissor rect and where the
+    // stencil test passes. Call it after the opaque draws and before
+    // `resolve`.
+    pub fn draw_skybox(&mut self) {
+        if self.environment.is_none() || !self.color_mask.any() {
+            return;
+        }
+
+        let inverse_projection = self.frame_projection().inverse();
+        let bounds = self.raster_bounds();
+        if bounds.is_empty() {
+            return;
+        }
+        let sampling_count = self.sample_positions.len();
+
+        for y in bounds.y0 as usize..=bounds.y1 as usize {
+            for x in bounds.x0 as usize..=bounds.x1 as usize {
+                for s in 0..sampling_count {
+                    let index = self.get_index(x, y) * sampling_count + s;
+                    if self.color_coverage[index] || !self.test_stencil(index, true) {
+                        continue;
+                    }
+
+                    let p = vec2(x as f32, y as f32) + self.sample_positions[s];
+                    let vp = &self.viewport;
+                    let ndc = vec2(
+                        (p.x - vp.x0) / (vp.x1 - vp.x0) * 2. - 1.,
+                        (p.y - vp.y0) / (vp.y1 - vp.y0) * 2. - 1.,
+                    );
+                    let far_point = inverse_projection * vec4(ndc.x, ndc.y, 1., 1.);
+                    let direction = far_point.xyz() / far_point.w;
+
+                    let radiance = self.environment.as_ref().unwrap().radiance(direction);
+                    let dst = self.frame_buf_supersampled[index];
+                    self.frame_buf_supersampled[index] =
+                        Vec4::select(self.color_mask, radiance.extend(1.), dst);
+                }
+            }
+        }
+    }
+
     // pub fn rasterize_triangle(&mut self, t: &Triangle) {
     //     let bbox = t.bounding_box();
     //     for i in bbox.x_range() {
@@ -545,7 +1589,7 @@ impl TextureConvertible for Rasterizer {
     }
 
     fn contents(&self) -> *const c_void {
-        self.frame_buf.as_ptr() as _
+        self.display_buf.as_ptr() as _
     }
 
     fn bytes_per_pixel(&self) -> usize {
@@ -553,16 +1597,12 @@ impl TextureConvertible for Rasterizer {
     }
 
     fn dump_u8norm(&self) -> Vec<u8> {
-        self.frame_buf
+        // the output transform keeps the colors in [0, 1] already, round to
+        // the nearest level instead of truncating
+        let quantize = |c: f32| (c.clamp(0., 1.) * 255.0).round() as u8;
+        self.display_buf
             .iter()
-            .flat_map(|it| {
-                [
-                    (it.x * 255.0) as u8,
-                    (it.y * 255.0) as u8,
-                    (it.z * 255.0) as u8,
-                    (it.w * 255.0) as u8,
-                ]
-            })
+            .flat_map(|it| it.to_array().map(quantize))
             .collect()
     }
 }
@@ -1,22 +1,201 @@
+use std::{
+    f32::consts::PI,
+    ops::{Index, IndexMut},
+};
+
 use glam::*;
 
-use crate::{texture::Texture, rasterizer::PosBufId};
+use crate::{texture::Texture, rasterizer::PosBufId, shadow::ShadowMap, mesh::Material, ibl::Environment};
 
 pub struct FragmentShaderPayload<'a> {
-    view_pos: Vec3,
-    color: Vec3,
-    normal: Vec3,
-    tex_coords: Vec2,
-    texture: &'a Texture
+    pub view_pos: Vec3,
+    pub color: Vec3,
+    pub normal: Vec3,
+    // xyz is the view-space tangent, w is the handedness of the bitangent
+    pub tangent: Vec4,
+    pub tex_coords: Vec2,
+    pub texture: &'a Texture,
+    pub normal_map: Option<&'a Texture>,
+    pub material: Option<&'a Material>,
+    // set per draw call, lets a deferred pass tell surfaces apart
+    pub material_id: u32,
+    pub lights: &'a [Light],
+    pub shadow_maps: &'a [ShadowMap],
+    // ambient light, a flat `AMBIENT_LIGHT_INTENSITY` without one
+    pub environment: Option<&'a Environment>,
+    // scales the ambient light, 1 without SSAO
+    pub ambient_occlusion: f32,
+}
+
+// Buffers a fragment shader can write to. `Color` is the regular frame
+// buffer, the others make up the G-buffer for deferred shading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RenderTarget {
+    Color,
+    Albedo,
+    Normal,
+    ViewPosition,
+    // the id in x
+    MaterialId,
+}
+
+impl RenderTarget {
+    pub const ALL: [RenderTarget; 5] = [
+        RenderTarget::Color,
+        RenderTarget::Albedo,
+        RenderTarget::Normal,
+        RenderTarget::ViewPosition,
+        RenderTarget::MaterialId,
+    ];
+}
+
+// One value per render target, indexed by the target
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FragmentOutput([Vec4; RenderTarget::ALL.len()]);
+
+impl Index<RenderTarget> for FragmentOutput {
+    type Output = Vec4;
+
+    fn index(&self, target: RenderTarget) -> &Vec4 {
+        &self.0[target as usize]
+    }
+}
+
+impl IndexMut<RenderTarget> for FragmentOutput {
+    fn index_mut(&mut self, target: RenderTarget) -> &mut Vec4 {
+        &mut self.0[target as usize]
+    }
+}
+
+// What a deferred lighting pass reads back from the G-buffer for a sample
+pub struct GBufferSample {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub view_pos: Vec3,
+    pub material_id: u32,
 }
 
 pub struct VertexShaderPayload {
-    position: Vec3
+    pub position: Vec3
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LightKind {
+    // falls off with the squared distance
+    Point,
+    // infinitely far away, shines along `direction` with the same intensity
+    // everywhere and `position` is unused
+    Directional { direction: Vec3 },
+    // a point light restricted to a cone around `direction`, fading out
+    // between the inner and outer half angles in degrees
+    Spot {
+        direction: Vec3,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    // a rectangle centered at `position` with half extents `right` and `up`,
+    // lighting the side of `right × up`. Integrated with `samples`² points.
+    RectArea { right: Vec3, up: Vec3, samples: u32 },
 }
 
+// Positions and directions are in view space
+#[derive(Debug, Clone, Copy)]
 pub struct Light {
     pub position: Vec3,
     pub intensity: Vec3,
+    pub kind: LightKind,
+}
+
+impl Light {
+    pub fn point(position: Vec3, intensity: Vec3) -> Self {
+        Self {
+            position,
+            intensity,
+            kind: LightKind::Point,
+        }
+    }
+
+    pub fn directional(direction: Vec3, intensity: Vec3) -> Self {
+        Self {
+            position: Vec3::ZERO,
+            intensity,
+            kind: LightKind::Directional {
+                direction: direction.normalize(),
+            },
+        }
+    }
+
+    pub fn spot(
+        position: Vec3,
+        direction: Vec3,
+        intensity: Vec3,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> Self {
+        Self {
+            position,
+            intensity,
+            kind: LightKind::Spot {
+                direction: direction.normalize(),
+                inner_angle,
+                outer_angle,
+            },
+        }
+    }
+
+    pub fn rect_area(position: Vec3, right: Vec3, up: Vec3, intensity: Vec3) -> Self {
+        Self {
+            position,
+            intensity,
+            kind: LightKind::RectArea {
+                right,
+                up,
+                samples: 4,
+            },
+        }
+    }
+
+    // The light arriving at `point` as (direction towards the light,
+    // intensity after falloff) pairs, one per sample of an area light
+    pub fn incident(&self, point: Vec3) -> Vec<(Vec3, Vec3)> {
+        let towards = |position: Vec3| {
+            let d = position - point;
+            (d.normalize(), self.intensity / d.length_squared())
+        };
+
+        match self.kind {
+            LightKind::Point => vec![towards(self.position)],
+            LightKind::Directional { direction } => vec![(-direction, self.intensity)],
+            LightKind::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+            } => {
+                let (l, intensity) = towards(self.position);
+                let cos_inner = inner_angle.to_radians().cos();
+                let cos_outer = outer_angle.to_radians().cos();
+                let t = ((-l).dot(direction) - cos_outer) / (cos_inner - cos_outer).max(1e-6);
+                let cone = t.clamp(0., 1.);
+                vec![(l, intensity * cone * cone * (3. - 2. * cone))]
+            }
+            LightKind::RectArea { right, up, samples } => {
+                let emitter_normal = right.cross(up).normalize();
+                let n = samples.max(1);
+                let mut incident = Vec::with_capacity((n * n) as usize);
+                for i in 0..n {
+                    for j in 0..n {
+                        let u = (i as f32 + 0.5) / n as f32 * 2. - 1.;
+                        let v = (j as f32 + 0.5) / n as f32 * 2. - 1.;
+                        let (l, intensity) = towards(self.position + right * u + up * v);
+                        // the rectangle is dimmer seen at a grazing angle
+                        let cos = (-l).dot(emitter_normal).max(0.);
+                        incident.push((l, intensity * cos / (n * n) as f32));
+                    }
+                }
+                incident
+            }
+        }
+    }
 }
 
 
@@ -24,48 +203,101 @@ pub type VertexShaderFn = fn(payload: VertexShaderPayload) -> Vec3;
 
 pub type FragmentShaderFn = fn(payload: FragmentShaderPayload) -> Vec4;
 
+pub type MrtFragmentShaderFn = fn(payload: FragmentShaderPayload) -> FragmentOutput;
+
+// `material` is the one `material_id` of the sample refers to, if any
+pub type DeferredShaderFn =
+    fn(sample: &GBufferSample, material: Option<&Material>, lighting: &Lighting) -> Vec4;
+
 
 pub fn vertex_shader(payload: VertexShaderPayload) -> Vec3 {
     payload.position
 }
 
-pub fn texture_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
-    let texture_color = payload.texture.get_color(payload.tex_coords.x, payload.tex_coords.y);
-    
-    let ka = vec3(0.005, 0.005, 0.005);
-    let kd = texture_color / 255.;
-    let ks = vec3(0.7937, 0.7937, 0.7937);
+const AMBIENT_LIGHT_INTENSITY: Vec3 = Vec3::splat(10.);
 
-    let l1 = Light {
-        position: vec3(20., 20., 20.),
-        intensity: vec3(500., 500., 500.),
-    };
+// The shadow map of the light at `light` in the light list, lights without
+// one aren't shadowed. Area lights share the shadow of their center.
+fn shadow_visibility(light: usize, shadow_maps: &[ShadowMap], point: Vec3, normal: Vec3) -> f32 {
+    shadow_maps
+        .iter()
+        .find(|it| it.light == light)
+        .map_or(1., |it| it.visibility(point, normal))
+}
 
-    let l2 = Light {
-        position: vec3(-20., 20., 0.),
-        intensity: vec3(500., 500., 500.),
-    };
+// Everything lighting a fragment besides its material
+pub struct Lighting<'a> {
+    pub lights: &'a [Light],
+    pub shadow_maps: &'a [ShadowMap],
+    pub environment: Option<&'a Environment>,
+    pub ambient_occlusion: f32,
+}
 
-    let lights = vec![l1, l2];
+impl<'a> Lighting<'a> {
+    fn of(payload: &FragmentShaderPayload<'a>) -> Self {
+        Self {
+            lights: payload.lights,
+            shadow_maps: payload.shadow_maps,
+            environment: payload.environment,
+            ambient_occlusion: payload.ambient_occlusion,
+        }
+    }
+}
 
-    let amb_light_intensity = vec3(10., 10., 10.);
-    let eye_pos = vec3(0., 0., 10.);
+fn blinn_phong(
+    ka: Vec3,
+    kd: Vec3,
+    ks: Vec3,
+    p: f32,
+    point: Vec3,
+    normal: Vec3,
+    lighting: &Lighting,
+) -> Vec3 {
+    let amb_light_intensity = AMBIENT_LIGHT_INTENSITY;
 
-    let p = 150f32;
+    // the eye is at the origin of view space
+    let view_direction = (-point).normalize();
 
-    let color = texture_color;
-    let point = payload.view_pos;
-    let normal = payload.normal;
+    let mut result_color = vec3(0., 0., 0.);
 
-    let result_color = vec3(0., 0., 0.);
-    
-    for light in lights {
+    // the environment replaces the flat ambient term, lighting the diffuse
+    // color like the lights do. Either is added once, not per light, and
+    // isn't shadowed.
+    let ambient = match lighting.environment {
+        Some(environment) => kd * environment.irradiance(normal),
+        None => amb_light_intensity * ka,
+    };
+    result_color += ambient * lighting.ambient_occlusion;
 
+    for (index, light) in lighting.lights.iter().enumerate() {
+        let visibility = shadow_visibility(index, lighting.shadow_maps, point, normal);
+
+        for (light_direction, intensity) in light.incident(point) {
+            let ld = intensity * kd * normal.dot(light_direction).max(0.);
+            let h = (light_direction + view_direction).normalize();
+            let ls = intensity * ks * normal.dot(h).max(0.).powf(p);
+            result_color += (ld + ls) * visibility;
+        }
     }
 
+    result_color
+}
 
-    result_color.extend(1.)
+pub fn texture_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
+    let texture_color = srgb_to_linear(payload.texture.get_color_by_tex_coord(payload.tex_coords));
+    
+    let ka = vec3(0.005, 0.005, 0.005);
+    let kd = texture_color;
+    let ks = vec3(0.7937, 0.7937, 0.7937);
+
+    let p = 150f32;
+
+    let point = payload.view_pos;
+    let normal = payload.normal.normalize();
 
+    let result_color = blinn_phong(ka, kd, ks, p, point, normal, &Lighting::of(&payload));
+
+    result_color.extend(1.)
 }
 
 
@@ -74,33 +306,12 @@ pub fn phong_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
     let kd = payload.color;
     let ks = vec3(0.7937, 0.7937, 0.7937);
 
-    let l1 = Light {
-        position: vec3(20., 20., 20.),
-        intensity: vec3(500., 500., 500.),
-    };
-
-    let l2 = Light {
-        position: vec3(-20., 20., 0.),
-        intensity: vec3(500., 500., 500.),
-    };
-
-    let lights = vec![l1, l2];
-
-    let amb_light_intensity = vec3(10., 10., 10.);
-    let eye_pos = vec3(0., 0., 10.);
-
     let p = 150f32;
 
-    let color = payload.color;
     let point = payload.view_pos;
-    let normal = payload.normal;
-
-    let result_color = vec3(0., 0., 0.);
-    
-    for light in lights {
-
-    }
+    let normal = payload.normal.normalize();
 
+    let result_color = blinn_phong(ka, kd, ks, p, point, normal, &Lighting::of(&payload));
 
     result_color.extend(1.)
 }
@@ -110,17 +321,7 @@ pub fn displacement_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
     let kd = payload.color;
     let ks = vec3(0.7937, 0.7937, 0.7937);
 
-    let l1 = Light {
-        position: vec3(20., 20., 20.),
-        intensity: vec3(500., 500., 500.),
-    };
-
-    let l2 = Light {
-        position: vec3(-20., 20., 0.),
-        intensity: vec3(500., 500., 500.),
-    };
-
-    let lights = vec![l1, l2];
+    let lights = payload.lights;
 
     let amb_light_intensity = vec3(10., 10., 10.);
     let eye_pos = vec3(0., 0., 10.);
@@ -152,17 +353,7 @@ pub fn bump_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
     let kd = payload.color;
     let ks = vec3(0.7937, 0.7937, 0.7937);
 
-    let l1 = Light {
-        position: vec3(20., 20., 20.),
-        intensity: vec3(500., 500., 500.),
-    };
-
-    let l2 = Light {
-        position: vec3(-20., 20., 0.),
-        intensity: vec3(500., 500., 500.),
-    };
-
-    let lights = vec![l1, l2];
+    let lights = payload.lights;
 
     let amb_light_intensity = vec3(10., 10., 10.);
     let eye_pos = vec3(0., 0., 10.);
@@ -190,4 +381,195 @@ pub fn bump_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
 
 
     result_color.extend(1.)
-}
\ No newline at end of file
+}
+
+
+
+// Looks up a tangent-space normal map and brings the normal to view space
+fn tangent_space_normal(n: Vec3, tangent: Vec4, normal_map: &Texture, tex_coords: Vec2) -> Vec3 {
+    // Gram-Schmidt the interpolated tangent against the normal,
+    // the bitangent takes its handedness from the uv winding
+    let t = (tangent.xyz() - n * n.dot(tangent.xyz())).normalize_or_zero();
+    let b = n.cross(t) * tangent.w;
+    let tbn = Mat3::from_cols(t, b, n);
+
+    // RGB in [0, 1] encodes a tangent-space normal in [-1, 1]
+    let ln = normal_map.get_color_by_tex_coord(tex_coords) * 2. - 1.;
+    (tbn * ln).normalize()
+}
+
+pub fn normal_map_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
+    let texture_color = srgb_to_linear(payload.texture.get_color_by_tex_coord(payload.tex_coords));
+
+    let ka = vec3(0.005, 0.005, 0.005);
+    let kd = texture_color;
+    let ks = vec3(0.7937, 0.7937, 0.7937);
+
+    let p = 150f32;
+
+    let point = payload.view_pos;
+    let n = payload.normal.normalize();
+
+    let normal = match payload.normal_map {
+        Some(normal_map) => tangent_space_normal(n, payload.tangent, normal_map, payload.tex_coords),
+        None => n,
+    };
+
+    let result_color = blinn_phong(ka, kd, ks, p, point, normal, &Lighting::of(&payload));
+
+    result_color.extend(1.)
+}
+
+// Fills the G-buffer instead of lighting, see `deferred_phong_shader`
+pub fn gbuffer_fragment_shader(payload: FragmentShaderPayload) -> FragmentOutput {
+    let albedo = srgb_to_linear(payload.texture.get_color_by_tex_coord(payload.tex_coords));
+
+    let mut output = FragmentOutput::default();
+    output[RenderTarget::Albedo] = albedo.extend(1.);
+    output[RenderTarget::Normal] = payload.normal.normalize().extend(0.);
+    output[RenderTarget::ViewPosition] = payload.view_pos.extend(1.);
+    output[RenderTarget::MaterialId] = vec4(payload.material_id as f32, 0., 0., 0.);
+    output
+}
+
+// Same Blinn-Phong model as the forward shaders, run once per G-buffer
+// sample. The material tints the albedo, sets the highlight from its
+// roughness and adds its emission.
+pub fn deferred_phong_shader(
+    sample: &GBufferSample,
+    material: Option<&Material>,
+    lighting: &Lighting,
+) -> Vec4 {
+    let ka = vec3(0.005, 0.005, 0.005);
+    let ks = vec3(0.7937, 0.7937, 0.7937);
+
+    let (kd, p, emissive) = match material {
+        // inverse of the usual Blinn-Phong exponent to GGX roughness mapping
+        Some(material) => (
+            sample.albedo * material.base_color.xyz(),
+            2. / (material.roughness * material.roughness).max(1e-4) - 2.,
+            material.emissive,
+        ),
+        None => (sample.albedo, 150., Vec3::ZERO),
+    };
+
+    let result_color =
+        emissive + blinn_phong(ka, kd, ks, p, sample.view_pos, sample.normal, lighting);
+
+    result_color.extend(1.)
+}
+
+// Color textures are stored sRGB encoded, lighting happens on linear values
+fn srgb_to_linear(color: Vec3) -> Vec3 {
+    let decode = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    vec3(decode(color.x), decode(color.y), decode(color.z))
+}
+
+// Trowbridge-Reitz GGX normal distribution, `a` is the squared roughness
+fn distribution_ggx(n_dot_h: f32, a: f32) -> f32 {
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+// Smith's masking-shadowing with the Schlick-GGX approximation, using the
+// k of direct lighting
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = (roughness + 1.) * (roughness + 1.) / 8.;
+    let g1 = |n_dot_x: f32| n_dot_x / (n_dot_x * (1. - k) + k);
+    g1(n_dot_v) * g1(n_dot_l)
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 {
+    f0 + (1. - f0) * (1. - cos_theta).clamp(0., 1.).powi(5)
+}
+
+// Cook-Torrance with the glTF metallic-roughness material: the factors of
+// the material are multiplied with its textures, roughness is in the green
+// and metallic in the blue channel. The alpha comes from the vertex colors.
+pub fn pbr_fragment_shader(payload: FragmentShaderPayload) -> Vec4 {
+    let material = match payload.material {
+        Some(material) => material,
+        None => panic!("PBR shading needs a material"),
+    };
+    let sample = |texture: &Option<Texture>| {
+        texture
+            .as_ref()
+            .map(|it| it.get_color_by_tex_coord(payload.tex_coords))
+    };
+
+    let base_color = material.base_color.xyz()
+        * sample(&material.base_color_texture).map_or(Vec3::ONE, srgb_to_linear);
+    let metallic_roughness = sample(&material.metallic_roughness_texture).unwrap_or(Vec3::ONE);
+    let roughness = (material.roughness * metallic_roughness.y).clamp(0.04, 1.);
+    let metallic = (material.metallic * metallic_roughness.z).clamp(0., 1.);
+    let occlusion = sample(&material.occlusion_texture).map_or(1., |it| it.x);
+    let emissive =
+        material.emissive * sample(&material.emissive_texture).map_or(Vec3::ONE, srgb_to_linear);
+
+    let point = payload.view_pos;
+    let n = payload.normal.normalize();
+    let normal = match &material.normal_texture {
+        Some(normal_map) => tangent_space_normal(n, payload.tangent, normal_map, payload.tex_coords),
+        None => n,
+    };
+
+    // the eye is at the origin of view space
+    let view_direction = (-point).normalize();
+    let n_dot_v = normal.dot(view_direction).max(1e-4);
+
+    // dielectrics reflect about 4% head on, metals tint it with their color
+    let f0 = Vec3::splat(0.04).lerp(base_color, metallic);
+    let a = roughness * roughness;
+
+    let mut result_color = Vec3::ZERO;
+
+    for (index, light) in payload.lights.iter().enumerate() {
+        let visibility = shadow_visibility(index, payload.shadow_maps, point, normal);
+
+        for (light_direction, intensity) in light.incident(point) {
+            let n_dot_l = normal.dot(light_direction);
+            if n_dot_l <= 0. {
+                continue;
+            }
+            let h = (light_direction + view_direction).normalize();
+
+            let d = distribution_ggx(normal.dot(h).max(0.), a);
+            let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+            let f = fresnel_schlick(h.dot(view_direction), f0);
+            let specular = d * g * f / (4. * n_dot_v * n_dot_l);
+
+            // what isn't reflected is diffused, except by metals
+            let kd = (1. - f) * (1. - metallic);
+            result_color += (kd * base_color / PI + specular) * intensity * n_dot_l * visibility;
+        }
+    }
+
+    let ambient = match payload.environment {
+        Some(environment) => {
+            // split-sum IBL, the Fresnel term accounts for roughness since
+            // the reflections come from the whole lobe
+            let f = f0 + (Vec3::splat(1. - roughness).max(f0) - f0) * (1. - n_dot_v).powi(5);
+            let kd = (1. - f) * (1. - metallic);
+            let diffuse = kd * base_color * environment.irradiance(normal);
+
+            let reflected = 2. * n_dot_v * normal - view_direction;
+            let brdf = environment.brdf(n_dot_v, roughness);
+            let specular = environment.specular(reflected, roughness) * (f * brdf.x + brdf.y);
+            diffuse + specular
+        }
+        None => {
+            let ka = 0.005;
+            AMBIENT_LIGHT_INTENSITY * ka * base_color
+        }
+    };
+    result_color += ambient * occlusion * payload.ambient_occlusion + emissive;
+
+    result_color.extend(1.)
+}
@@ -0,0 +1,116 @@
+use glam::*;
+
+use crate::{rasterizer::Rasterizer, transform::get_projection_matrix};
+
+// Depth of the scene as seen from a light, used by the shaders to tell
+// whether a point is lit. Positions are in the camera's view space, like the
+// lights in the shaders.
+pub struct ShadowMap {
+    // index of the light it belongs to in `Rasterizer::set_lights`
+    pub light: usize,
+    pub light_position: Vec3,
+    // camera view space to the clip space of the light
+    view_projection: Mat4,
+    near: f32,
+    far: f32,
+    size: usize,
+    // distance from the light along its axis, rows from top to bottom
+    depth: Vec<f32>,
+
+    // in view space units, grows towards grazing angles up to `slope_bias`
+    pub bias: f32,
+    pub slope_bias: f32,
+    // texels on each side of the lookup that get averaged, 0 is a hard edge
+    pub pcf_radius: i32,
+}
+
+impl ShadowMap {
+    // A perspective shadow map of `size`² texels for the light at index
+    // `light`, from `light_position` looking at `target`. `fov` in degrees
+    // should cover the shadow casters.
+    pub fn new(light: usize, light_position: Vec3, target: Vec3, fov: f32, size: usize) -> Self {
+        let (near, far) = (0.5, 200.);
+
+        let up = if (target - light_position).cross(Vec3::Y).length_squared() < 1e-6 {
+            Vec3::Z
+        } else {
+            Vec3::Y
+        };
+        let light_view = Mat4::look_at_rh(light_position, target, up);
+        let projection = get_projection_matrix(fov, 1., near, far);
+
+        Self {
+            light,
+            light_position,
+            view_projection: projection * light_view,
+            near,
+            far,
+            size,
+            depth: vec![f32::INFINITY; size * size],
+            bias: 0.02,
+            slope_bias: 0.1,
+            pcf_radius: 1,
+        }
+    }
+
+    // A depth-only rasterizer looking from the light, draw the shadow casters
+    // with it using their usual model matrix and pass it to `capture`
+    pub fn rasterizer(&self, camera_view: Mat4) -> Rasterizer {
+        let mut r = Rasterizer::new(self.size, self.size, 1);
+        r.set_view(self.view_projection * camera_view);
+        r.set_color_write(false);
+        r
+    }
+
+    // Keeps the depth rendered by `r`, linearized so the bias is in view
+    // space units
+    pub fn capture(&mut self, r: &Rasterizer) {
+        let (n, f) = (self.near, self.far);
+        self.depth = r
+            .resolve_depth()
+            .into_iter()
+            .map(|d| {
+                let z = d * 2. - 1.;
+                2. * f * n / ((f + n) - z * (f - n))
+            })
+            .collect();
+    }
+
+    // Fraction of the PCF kernel around `point` that the light reaches
+    pub fn visibility(&self, point: Vec3, normal: Vec3) -> f32 {
+        let clip = self.view_projection * point.extend(1.);
+        if clip.w <= 0. {
+            return 1.;
+        }
+
+        let ndc = clip.xyz() / clip.w;
+        if ndc.x.abs() > 1. || ndc.y.abs() > 1. {
+            return 1.;
+        }
+
+        let light_direction = (self.light_position - point).normalize();
+        let bias = self
+            .bias
+            .max(self.slope_bias * (1. - normal.dot(light_direction).max(0.)));
+        let depth = clip.w - bias;
+
+        let size = self.size as i32;
+        let x = ((ndc.x + 1.) * 0.5 * self.size as f32) as i32;
+        let row = ((1. - ndc.y) * 0.5 * self.size as f32) as i32;
+
+        let mut lit = 0;
+        let mut count = 0;
+        for dy in -self.pcf_radius..=self.pcf_radius {
+            for dx in -self.pcf_radius..=self.pcf_radius {
+                let x = (x + dx).clamp(0, size - 1);
+                let row = (row + dy).clamp(0, size - 1);
+                if depth <= self.depth[(row * size + x) as usize] {
+                    lit += 1;
+                }
+                count += 1;
+            }
+        }
+
+        lit as f32 / count as f32
+    }
+}
@@ -0,0 +1,200 @@
+use std::f32::consts::PI;
+
+use glam::*;
+
+use crate::postprocess::halton;
+
+// the random rotations of the kernel repeat every this many pixels, the blur
+// averages over the same footprint
+const NOISE_SIZE: usize = 4;
+
+// Screen-space ambient occlusion: for every pixel a hemisphere of samples
+// around its normal is compared against the depth buffer, and the fraction
+// that ends up behind other geometry darkens the ambient light
+pub struct Ssao {
+    // of the hemisphere, in view space units
+    pub radius: f32,
+    // keeps flat surfaces from occluding themselves
+    pub bias: f32,
+    pub kernel_size: usize,
+    // in pixels, 0 leaves the noise of the rotated kernels
+    pub blur_radius: i32,
+    // > 1 darkens the occlusion
+    pub power: f32,
+}
+
+impl Default for Ssao {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            bias: 0.025,
+            kernel_size: 16,
+            blur_radius: 2,
+            power: 1.5,
+        }
+    }
+}
+
+impl Ssao {
+    // Points in the +z hemisphere, more of them close to the center
+    fn kernel(&self) -> Vec<Vec3> {
+        (0..self.kernel_size)
+            .map(|i| {
+                let sample = vec3(
+                    halton(i + 1, 2) * 2. - 1.,
+                    halton(i + 1, 3) * 2. - 1.,
+                    halton(i + 1, 5),
+                )
+                .normalize_or_zero();
+                let t = i as f32 / self.kernel_size as f32;
+                sample * (0.1 + 0.9 * t * t)
+            })
+            .collect()
+    }
+
+    // Ambient visibility per pixel in [0, 1], rows from top to bottom.
+    // `positions` are the view space positions of the pixels, None where
+    // nothing was drawn, and `projection` maps them onto the whole frame.
+    pub fn compute(
+        &self,
+        positions: &[Option<Vec3>],
+        w: usize,
+        h: usize,
+        projection: Mat4,
+    ) -> Vec<f32> {
+        let at = |x: isize, y: isize| {
+            if x < 0 || y < 0 || x >= w as isize || y >= h as isize {
+                None
+            } else {
+                positions[y as usize * w + x as usize]
+            }
+        };
+        let to_pixel = |p: Vec3| {
+            let clip = projection * p.extend(1.);
+            let ndc = clip.xy() / clip.w;
+            let x = ((ndc.x + 1.) * 0.5 * w as f32).floor() as isize;
+            let y = ((1. - ndc.y) * 0.5 * h as f32).floor() as isize;
+            (x, y)
+        };
+
+        let kernel = self.kernel();
+        let mut occlusion = vec![1.; w * h];
+
+        for y in 0..h as isize {
+            for x in 0..w as isize {
+                let Some(p) = at(x, y) else {
+                    continue;
+                };
+                let normal = reconstruct_normal(p, at, x, y);
+
+                // rotate the kernel around the normal differently for
+                // every pixel of the noise tile
+                let tile = (y as usize % NOISE_SIZE) * NOISE_SIZE + x as usize % NOISE_SIZE;
+                let angle = halton(tile + 1, 2) * 2. * PI;
+                let random = vec3(angle.cos(), angle.sin(), 0.);
+                let tangent = (random - normal * random.dot(normal)).normalize_or_zero();
+                let tangent = if tangent == Vec3::ZERO {
+                    normal.any_orthonormal_vector()
+                } else {
+                    tangent
+                };
+                let tbn = Mat3::from_cols(tangent, normal.cross(tangent), normal);
+
+                let mut occluded = 0.;
+                for &k in &kernel {
+                    let sample = p + tbn * k * self.radius;
+                    let (sx, sy) = to_pixel(sample);
+                    let Some(scene) = at(sx, sy) else {
+                        continue;
+                    };
+
+                    // closer to the eye means a larger z, geometry far in
+                    // front of the point doesn't count
+                    if scene.z >= sample.z + self.bias {
+                        let range = (self.radius / (p.z - scene.z).abs()).min(1.);
+                        occluded += range * range * (3. - 2. * range);
+                    }
+                }
+
+                let visibility = 1. - occluded / kernel.len() as f32;
+                occlusion[y as usize * w + x as usize] = visibility.max(0.).powf(self.power);
+            }
+        }
+
+        self.blur(&occlusion, positions, w, h)
+    }
+
+    // Box blur over the noise tile that skips neighbours at a different
+    // depth, so the occlusion doesn't bleed across silhouettes
+    fn blur(&self, occlusion: &[f32], positions: &[Option<Vec3>], w: usize, h: usize) -> Vec<f32> {
+        let r = self.blur_radius as isize;
+        let mut out = occlusion.to_vec();
+
+        for y in 0..h as isize {
+            for x in 0..w as isize {
+                let Some(p) = positions[y as usize * w + x as usize] else {
+                    continue;
+                };
+                let tolerance = self.radius * 0.5;
+
+                let mut sum = 0.;
+                let mut weight = 0.;
+                for dy in -r..=r {
+                    for dx in -r..=r {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx < 0 || ny < 0 || nx >= w as isize || ny >= h as isize {
+                            continue;
+                        }
+                        let index = ny as usize * w + nx as usize;
+                        let Some(q) = positions[index] else {
+                            continue;
+                        };
+
+                        let similarity = (1. - (q.z - p.z).abs() / tolerance).max(0.);
+                        sum += occlusion[index] * similarity;
+                        weight += similarity;
+                    }
+                }
+                out[y as usize * w + x as usize] = sum / weight;
+            }
+        }
+
+        out
+    }
+}
+
+// From the neighbouring positions, on each axis the side with the smaller
+// depth step so the normal doesn't smear across edges. Faces the eye.
+fn reconstruct_normal(
+    p: Vec3,
+    at: impl Fn(isize, isize) -> Option<Vec3>,
+    x: isize,
+    y: isize,
+) -> Vec3 {
+    let closer = |a: Option<Vec3>, b: Option<Vec3>| match (a, b) {
+        (Some(a), Some(b)) => {
+            if (a.z - p.z).abs() < (b.z - p.z).abs() {
+                a - p
+            } else {
+                p - b
+            }
+        }
+        (Some(a), None) => a - p,
+        (None, Some(b)) => p - b,
+        (None, None) => Vec3::ZERO,
+    };
+
+    // rows go down the screen
+    let dx = closer(at(x + 1, y), at(x - 1, y));
+    let dy = closer(at(x, y - 1), at(x, y + 1));
+
+    let normal = dx.cross(dy).normalize_or_zero();
+    if normal == Vec3::ZERO {
+        return -p.normalize_or_zero();
+    }
+    if normal.dot(-p) < 0. {
+        -normal
+    } else {
+        normal
+    }
+}
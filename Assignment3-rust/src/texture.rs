@@ -1,16 +1,15 @@
 use glam::*;
-use image::Rgb;
+use image::{DynamicImage, Rgb};
 
+// Stored as floats so rendered and HDR content keeps its range,
+// 8-bit images are normalized to [0, 1]
+#[derive(Clone)]
 pub struct Texture {
-    image_data: image::RgbImage,
+    image_data: image::Rgb32FImage,
 }
 
-fn rgb_data_to_vec4(data: &Rgb<u8>) -> Vec3 {
-    vec3(
-        data[0] as f32 / 255.,
-        data[1] as f32 / 255.,
-        data[1] as f32 / 255.,
-    )
+fn rgb_data_to_vec4(data: &Rgb<f32>) -> Vec3 {
+    vec3(data[0], data[1], data[2])
 }
 
 fn interpolate(x0: u32, x1: u32, x: f32, y0: Vec3, y1: Vec3) -> Vec3 {
@@ -25,7 +24,19 @@ fn interpolate(x0: u32, x1: u32, x: f32, y0: Vec3, y1: Vec3) -> Vec3 {
 impl Texture {
     
     pub fn new(path: &str) -> Self {
-        let image_data = image::open(path).unwrap().into_rgb8();
+        let image_data = image::open(path).unwrap().into_rgb32f();
+        Self { image_data }
+    }
+
+    pub fn from_image(image_data: image::RgbImage) -> Self {
+        let image_data = DynamicImage::ImageRgb8(image_data).into_rgb32f();
+        Self { image_data }
+    }
+
+    // Interleaved RGB floats, rows from top to bottom
+    pub fn from_raw(width: u32, height: u32, data: Vec<f32>) -> Self {
+        let image_data = image::Rgb32FImage::from_raw(width, height, data)
+            .expect("Texture data doesn't match its size");
         Self { image_data }
     }
 
@@ -69,6 +80,35 @@ impl Texture {
         interpolate(y1, y2, y, c0, c1)
     }
 
+    // Tangent-space normal map from the red channel of a height map,
+    // `strength` scales the slopes. Rows go down like v.
+    pub fn normal_map_from_height(&self, strength: f32) -> Texture {
+        let (w, h) = (self.width() as i64, self.height() as i64);
+        let height = |x: i64, y: i64| {
+            let x = x.clamp(0, w - 1) as u32;
+            let y = y.clamp(0, h - 1) as u32;
+            self.image_data.get_pixel(x, y)[0]
+        };
+
+        let data = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let du = (height(x + 1, y) - height(x - 1, y)) * 0.5 * strength;
+                let dv = (height(x, y + 1) - height(x, y - 1)) * 0.5 * strength;
+                let n = vec3(-du, -dv, 1.).normalize();
+                (n * 0.5 + 0.5).to_array()
+            })
+            .collect();
+        Texture::from_raw(w as u32, h as u32, data)
+    }
+
+    // Clamped to 8 bits per channel
+    pub fn save(&self, path: &str) -> image::ImageResult<()> {
+        DynamicImage::ImageRgb32F(self.image_data.clone())
+            .into_rgb8()
+            .save(path)
+    }
+
     pub fn width(&self) -> u32 {
         self.image_data.width()
     }
@@ -21,18 +21,42 @@ pub fn get_model_matrix(rotation_angle_deg: f32) -> Mat4 {
     // ).transpose()
 }
 
+// OpenGL style projection, the eye looks down -z and the visible volume maps
+// to -w <= x, y, z <= w with w > 0, so triangles can be clipped before the
+// perspective divide
 pub fn get_projection_matrix(eye_fov_deg: f32, aspect_ratio: f32, z_near: f32, z_far: f32) -> Mat4 {
-    let top = -((eye_fov_deg/2.).to_radians() * z_near.abs()).tan();
+    let top = (eye_fov_deg / 2.).to_radians().tan() * z_near.abs();
     let right = top * aspect_ratio;
 
     mat4(
         vec4(z_near / right, 0., 0., 0.),
         vec4(0., z_near / top, 0., 0.),
-        vec4(0., 0., (z_near + z_far) / (z_near - z_far), (2. * z_near * z_far) / (z_far - z_near)),
-        vec4(0., 0., 1., 0.),
+        vec4(0., 0., (z_near + z_far) / (z_near - z_far), (2. * z_near * z_far) / (z_near - z_far)),
+        vec4(0., 0., -1., 0.),
     ).transpose()
 }
 
+// Reversed-Z version of `get_projection_matrix` for `Rasterizer::set_reversed_z`,
+// z / w is 1 at the near plane and falls to 0 at the far plane, which can be
+// at infinity. Depth then keeps its precision far away where floats are
+// densest, instead of rounding through `1 - small`.
+pub fn get_reversed_projection_matrix(eye_fov_deg: f32, aspect_ratio: f32, z_near: f32, z_far: f32) -> Mat4 {
+    let top = (eye_fov_deg / 2.).to_radians().tan() * z_near.abs();
+    let right = top * aspect_ratio;
+
+    let (a, b) = if z_far.is_infinite() {
+        (0., z_near)
+    } else {
+        (z_near / (z_far - z_near), z_near * z_far / (z_far - z_near))
+    };
+
+    mat4(
+        vec4(z_near / right, 0., 0., 0.),
+        vec4(0., z_near / top, 0., 0.),
+        vec4(0., 0., a, b),
+        vec4(0., 0., -1., 0.),
+    ).transpose()
+}
 
 pub fn get_rotation(axis: Vec3, deg: f32) -> Mat4 {
     let angle = deg.to_radians();
@@ -8,6 +8,8 @@ pub struct Triangle {
     pub color: [Vec4; 3],
     pub tex_coords: [Vec2; 3],
     pub normal: [Vec3; 3],
+    // xyz is the tangent, w the handedness of the bitangent
+    pub tangent: [Vec4; 3],
 }
 
 pub struct Rect {
@@ -47,6 +49,11 @@ impl Triangle {
                 vec3(0.0, 0.0, 0.0),
                 vec3(0.0, 0.0, 0.0),
             ],
+            tangent: [
+                vec4(0.0, 0.0, 0.0, 0.0),
+                vec4(0.0, 0.0, 0.0, 0.0),
+                vec4(0.0, 0.0, 0.0, 0.0),
+            ],
         }
     }
 
@@ -156,6 +163,26 @@ impl Triangle {
 }
 
 impl Rect {
+    pub fn new(x0: f32, y0: f32, x1: f32, y1: f32) -> Self {
+        Self { x0, y0, x1, y1 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.x0 > self.x1 || self.y0 > self.y1
+    }
+
+    pub fn clamp(&self, bounds: &Rect) -> Rect {
+        Rect {
+            x0: self.x0.max(bounds.x0),
+            y0: self.y0.max(bounds.y0),
+            x1: self.x1.min(bounds.x1),
+            y1: self.y1.min(bounds.y1),
+        }
+    }
+
+    // Both ends are the pixel containing the corner, so the ranges below
+    // cover every pixel the rect touches. Only valid for non-negative rects,
+    // clamp to the screen first.
     pub fn x_start(&self) -> usize {
         self.x0.floor() as _
     }
@@ -169,7 +196,7 @@ impl Rect {
     }
 
     pub fn y_end(&self) -> usize {
-        self.y1.ceil() as _
+        self.y1.floor() as _
     }
 
     pub fn x_range(&self) -> Range<usize> {